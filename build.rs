@@ -1,10 +1,72 @@
 use std::env;
 use std::fs;
-use cc;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Oldest server whose version-1 calling convention structures are modelled by the fmgr module.
+const MIN_VERSION_NUM : i64 = 130000;
+
+// Major versions for which a pg_ge_[major] cfg is set when the target server is at least as recent.
+const VERSION_CFGS : [i64; 5] = [14, 15, 16, 17, 18];
+
+/// Reads the include directory from the pg_config binary (which can be overriden by the
+/// PG_CONFIG environment variable, to build against a server other than the first at PATH).
+fn pg_include_dir() -> PathBuf {
+    let pg_config = env::var("PG_CONFIG").unwrap_or_else(|_| String::from("pg_config"));
+    let out = Command::new(&pg_config)
+        .arg("--includedir")
+        .output()
+        .unwrap_or_else(|e| panic!("Could not run {} (set PG_CONFIG to the pg_config of the target server): {}", pg_config, e));
+    if !out.status.success() {
+        panic!("{} --includedir failed: {}", pg_config, String::from_utf8_lossy(&out.stderr));
+    }
+    PathBuf::from(String::from_utf8(out.stdout).unwrap().trim())
+}
+
+/// Returns the value of a #define NAME VALUE line from a header.
+fn define_value(header : &str, name : &str) -> Option<i64> {
+    header.lines().find_map(|line| {
+        let mut tokens = line.split_whitespace();
+        match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some("#define"), Some(n), Some(value)) if n == name => value.parse().ok(),
+            _ => None
+        }
+    })
+}
 
 fn main() {
-    cc::Build::new()
-        .file("src/pg_helper.c")
-        .include("/usr/include/postgresql/11/server")
-        .compile("pghelper");
+    // The values checked by the server when it loads the extension (Pg_magic_struct) are read
+    // from pg_config.h and pg_config_manual.h, which are installed with the client headers.
+    println!("cargo:rerun-if-env-changed=PG_CONFIG");
+    let include_dir = pg_include_dir();
+    let mut header = String::new();
+    for name in ["pg_config.h", "pg_config_manual.h"].iter() {
+        let path = include_dir.join(name);
+        println!("cargo:rerun-if-changed={}", path.display());
+        header += &fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Could not read {}: {}", path.display(), e));
+    }
+    let value = |name : &str| -> i64 {
+        define_value(&header, name).unwrap_or_else(|| panic!("Missing {} definition at server headers", name))
+    };
+    let version_num = value("PG_VERSION_NUM");
+    if version_num < MIN_VERSION_NUM {
+        panic!("PostgreSQL {} is not supported (the oldest supported version is 13)", version_num);
+    }
+    let mut consts = String::new();
+    consts += &format!("pub const PG_VERSION_NUM : i32 = {};\n", version_num);
+    consts += &format!("pub const FUNC_MAX_ARGS : i32 = {};\n", value("FUNC_MAX_ARGS"));
+    consts += &format!("pub const INDEX_MAX_KEYS : i32 = {};\n", value("INDEX_MAX_KEYS"));
+    consts += &format!("pub const NAMEDATALEN : i32 = {};\n", value("NAMEDATALEN"));
+    consts += &format!("pub const MAXIMUM_ALIGNOF : usize = {};\n", value("MAXIMUM_ALIGNOF"));
+    consts += &format!("pub const FLOAT8_BYVAL : bool = {};\n", value("SIZEOF_VOID_P") >= 8);
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("pg_config.rs");
+    fs::write(&out_path, consts).unwrap();
+
+    for major in VERSION_CFGS.iter() {
+        println!("cargo:rustc-check-cfg=cfg(pg_ge_{})", major);
+        if version_num >= major * 10000 {
+            println!("cargo:rustc-cfg=pg_ge_{}", major);
+        }
+    }
 }
@@ -0,0 +1,307 @@
+use proc_macro::TokenStream;
+use quote::{quote, format_ident};
+use syn::{parse_macro_input, AttributeArgs, ItemFn, ItemImpl, ItemStruct, FnArg};
+use pgserver_sql::{Function, Aggregate, EventTrigger};
+use pgserver_sql::aggregate::{SupportFunction, assoc_type, arg_types};
+use pgserver_sql::types::option_inner;
+
+/// Exports a Rust function to PostgreSQL. The annotated function is kept as written, and the
+/// attribute generates a V1 entry point ([name]_wrapper) that reads the arguments from the
+/// FunctionCallInfo structure, plus the pg_finfo_[name]_wrapper symbol the server looks up
+/// when it loads the function. The matching CREATE FUNCTION statement is written by pg_install,
+/// which scans the crate sources for the attribute.
+///
+/// Arguments and return values of type Option<T> map SQL NULL to None. Functions taking any
+/// Option argument are declared non-strict, so the server calls them even when arguments are
+/// NULL (a NULL passed to a non-Option argument raises an error).
+///
+/// Functions returning impl Iterator<Item = T> are declared as returning setof T, and their
+/// entry point produces one row per item (see pgserver::srf::next_value). Functions returning
+/// tuples or structs deriving PgRow return composite values; combined with iterators, they
+/// are declared as returning a table (see pgserver::row::IntoRow).
+///
+/// ```rust,ignore
+/// #[pg_extern(immutable)]
+/// fn text_len(t : Text) -> i32 {
+///     t.as_ref().len() as i32
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn pg_extern(attr : TokenStream, item : TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let item = parse_macro_input!(item as ItemFn);
+    let func = match Function::from_item(&item, &args) {
+        Ok(func) => func,
+        Err(e) => return e.to_compile_error().into()
+    };
+
+    let name = &item.sig.ident;
+    let arg_names : Vec<_> = (0..item.sig.inputs.len())
+        .map(|i| format_ident!("arg{}", i) )
+        .collect();
+    let arg_tys : Vec<_> = item.sig.inputs.iter()
+        .filter_map(|input| match input {
+            FnArg::Typed(pat_ty) => Some(&pat_ty.ty),
+            FnArg::Receiver(_) => None
+        }).collect();
+    let arg_ixs = 0..arg_names.len();
+    let read_args = quote! {
+        #( let #arg_names = ::pgserver::fmgr::arg::<#arg_tys>(fcinfo, #arg_ixs); )*
+    };
+    let body = match (func.returns_set, func.returns_row()) {
+        (true, composite) => {
+            let next = if composite { quote!(next_row) } else { quote!(next_value) };
+            quote! {
+                ::pgserver::srf::#next(fcinfo, || {
+                    #read_args
+                    #name(#(#arg_names),*)
+                })
+            }
+        },
+        (false, composite) => {
+            let ret = if composite { quote!(row::ret_row) } else { quote!(fmgr::ret) };
+            quote! {
+                #read_args
+                ::pgserver::#ret(fcinfo, #name(#(#arg_names),*))
+            }
+        }
+    };
+
+    let entry_point = entry_point(&func.symbol(), body);
+    let expanded = quote! {
+        #item
+
+        #entry_point
+    };
+    expanded.into()
+}
+
+/// Exports a Rust function as a trigger function. The function takes a &pgserver::trigger::Trigger
+/// and returns Option<pgserver::row::Row> (the row the operation proceeds with, or None to skip
+/// it), and is declared as returning trigger, so it can be used at CREATE TRIGGER. The attribute
+/// accepts the volatility and parallel_safe options of #[pg_extern].
+///
+/// ```rust,ignore
+/// #[pg_trigger]
+/// fn no_deletes(trigger : &Trigger) -> Option<Row> {
+///     None
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn pg_trigger(attr : TokenStream, item : TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let item = parse_macro_input!(item as ItemFn);
+    let func = match Function::from_trigger(&item, &args) {
+        Ok(func) => func,
+        Err(e) => return e.to_compile_error().into()
+    };
+    let name = &item.sig.ident;
+    let entry_point = entry_point(&func.symbol(), quote!(::pgserver::trigger::call(fcinfo, #name)));
+    let expanded = quote! {
+        #item
+
+        #entry_point
+    };
+    expanded.into()
+}
+
+/// Exports a Rust function taking a &pgserver::trigger::EventTrigger as an event trigger function
+/// (returning event_trigger). With event = "...", the extension script also creates an event
+/// trigger named after the function that calls it on that event, restricted to the command tags
+/// given with tags("CREATE TABLE", ...), if any:
+///
+/// ```rust,ignore
+/// #[pg_event_trigger(event = "ddl_command_start", tags("DROP TABLE"))]
+/// fn forbid_drops(trigger : &EventTrigger) {
+///     log::Error::raise("Tables cannot be dropped");
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn pg_event_trigger(attr : TokenStream, item : TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let item = parse_macro_input!(item as ItemFn);
+    let trigger = match EventTrigger::from_item(&item, &args) {
+        Ok(trigger) => trigger,
+        Err(e) => return e.to_compile_error().into()
+    };
+    let name = &item.sig.ident;
+    let entry_point = entry_point(&trigger.function.symbol(), quote!(::pgserver::trigger::call_event(fcinfo, #name)));
+    let expanded = quote! {
+        #item
+
+        #entry_point
+    };
+    expanded.into()
+}
+
+// Version-1 entry point named symbol, with its pg_finfo record. The body runs under a boundary,
+// so the server errors raised while it runs are raised again once its Rust frames are unwound,
+// and as the current call (see pgserver::fmgr::call).
+fn entry_point(symbol : &str, body : proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let wrapper = format_ident!("{}", symbol);
+    let finfo = format_ident!("pg_finfo_{}", symbol);
+    quote! {
+        #[no_mangle]
+        #[doc(hidden)]
+        pub extern "C" fn #finfo() -> &'static ::pgserver::fmgr::Pg_finfo_record {
+            &::pgserver::fmgr::FINFO_V1
+        }
+
+        #[no_mangle]
+        #[doc(hidden)]
+        pub unsafe extern "C" fn #wrapper(
+            fcinfo : ::pgserver::fmgr::FunctionCallInfo
+        ) -> ::pgserver::datum::Datum {
+            ::pgserver::error::boundary(|| ::pgserver::fmgr::call(fcinfo, || {
+                #body
+            }))
+        }
+    }
+}
+
+// Closure reading the arguments of an aggregate support function (starting after the state) as a
+// single value, a tuple, or (), returning None if a non-Option argument is NULL.
+fn read_agg_args(types : &[&syn::Type]) -> proc_macro2::TokenStream {
+    let arg_ixs : Vec<_> = (1..=types.len()).collect();
+    let null_checks = types.iter().zip(arg_ixs.iter())
+        .filter(|(ty, _)| option_inner(ty).is_none() )
+        .map(|(_, ix)| quote! {
+            if ::pgserver::fmgr::arg_is_null(fcinfo, #ix) {
+                return None;
+            }
+        });
+    let reads = types.iter().zip(arg_ixs.iter())
+        .map(|(ty, ix)| quote!(::pgserver::fmgr::arg::<#ty>(fcinfo, #ix)) );
+    let args = if types.len() == 1 {
+        quote!(#(#reads)*)
+    } else {
+        quote!((#(#reads,)*))
+    };
+    quote! {
+        || {
+            #(#null_checks)*
+            Some(#args)
+        }
+    }
+}
+
+/// Exports an implementation of pgserver::aggregate::Aggregate as an aggregate function. The
+/// attribute generates the entry points of the transition and final functions (and of the
+/// inverse transition, combine, serial and deserial functions, if the impl block defines retract,
+/// combine, serialize and deserialize). Besides name = "...", it accepts the options of #[pg_extern].
+/// Implementations of pgserver::aggregate::OrderedSetAggregate are exported as ordered-set
+/// aggregates (or hypothetical-set aggregates, given the hypothetical option).
+#[proc_macro_attribute]
+pub fn pg_aggregate(attr : TokenStream, item : TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let item = parse_macro_input!(item as ItemImpl);
+    let agg = match Aggregate::from_item(&item, &args) {
+        Ok(agg) => agg,
+        Err(e) => return e.to_compile_error().into()
+    };
+    let ty = &item.self_ty;
+
+    // Aggregated arguments follow the state (as do the direct arguments of the final function
+    // of ordered-set aggregates).
+    let read_args = read_agg_args(&arg_types(assoc_type(&item, "Args").unwrap()));
+    let read_direct = assoc_type(&item, "DirectArgs").map(|direct| read_agg_args(&arg_types(direct)) );
+
+    let mut entry_points = Vec::new();
+    for func in agg.support_functions() {
+        let body = match func {
+            SupportFunction::Transition if agg.direct_args.is_some() => {
+                quote!(::pgserver::aggregate::ordered_accum::<#ty, _>(fcinfo, #read_args))
+            },
+            SupportFunction::Final if agg.direct_args.is_some() => {
+                quote!(::pgserver::aggregate::ordered_finalize::<#ty, _>(fcinfo, #read_direct))
+            },
+            SupportFunction::Transition => quote!(::pgserver::aggregate::accum::<#ty, _>(fcinfo, #read_args)),
+            SupportFunction::Inverse => quote!(::pgserver::aggregate::retract::<#ty, _>(fcinfo, #read_args)),
+            SupportFunction::Final => quote!(::pgserver::aggregate::finalize::<#ty>(fcinfo)),
+            SupportFunction::Combine => quote!(::pgserver::aggregate::combine::<#ty>(fcinfo)),
+            SupportFunction::Serialize => quote!(::pgserver::aggregate::serialize::<#ty>(fcinfo)),
+            SupportFunction::Deserialize => quote!(::pgserver::aggregate::deserialize::<#ty>(fcinfo))
+        };
+        entry_points.push(entry_point(&agg.support_function(func).symbol(), body));
+    }
+
+    let expanded = quote! {
+        #item
+
+        #(#entry_points)*
+    };
+    expanded.into()
+}
+
+// Implementation of IntoRow shared by PgRow and PgComposite, listing the fields in order.
+fn into_row_impl(item : &ItemStruct) -> proc_macro2::TokenStream {
+    let name = &item.ident;
+    let fields = item.fields.iter().map(|f| f.ident.as_ref().unwrap() );
+    quote! {
+        impl ::pgserver::row::IntoRow for #name {
+            fn into_row(self) -> Option<Vec<Option<::pgserver::datum::Datum>>> {
+                Some(vec![#(::pgserver::datum::IntoDatum::into_datum(self.#fields)),*])
+            }
+        }
+    }
+}
+
+/// Implements pgserver::row::IntoRow for a struct with named fields, so it can be returned
+/// from #[pg_extern] functions as a composite value (or as the rows of a table function). The
+/// columns take the names of the fields, and the types of the fields must implement IntoDatum.
+#[proc_macro_derive(PgRow)]
+pub fn derive_pg_row(item : TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemStruct);
+    if let Err(e) = pgserver_sql::composite::row_columns(&item) {
+        return e.to_compile_error().into();
+    }
+    into_row_impl(&item).into()
+}
+
+/// Declares a composite type for a struct with named fields (CREATE TYPE [name] AS (...), with
+/// the struct name in snake case and one attribute per field, in order), and implements the
+/// conversions in both directions: IntoRow (to return the struct from functions), IntoDatum
+/// (to nest it into arrays or other composites) and FromDatum (to take it as an argument). The
+/// fields are read by name, and their types must implement both FromDatum and IntoDatum.
+#[proc_macro_derive(PgComposite)]
+pub fn derive_pg_composite(item : TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemStruct);
+    let composite = match pgserver_sql::Composite::from_item(&item) {
+        Ok(composite) => composite,
+        Err(e) => return e.to_compile_error().into()
+    };
+    let name = &item.ident;
+    let type_name = &composite.name;
+    let fields : Vec<_> = item.fields.iter().map(|f| f.ident.as_ref().unwrap() ).collect();
+    let field_names = fields.iter().map(|f| f.to_string() );
+    let into_row = into_row_impl(&item);
+    let expanded = quote! {
+        #into_row
+
+        impl ::pgserver::datum::IntoDatum for #name {
+
+            fn type_oid() -> ::pgserver::datum::Oid {
+                ::pgserver::row::composite_type_oid(#type_name)
+            }
+
+            fn into_datum(self) -> Option<::pgserver::datum::Datum> {
+                unsafe { ::pgserver::row::form_composite(<Self as ::pgserver::datum::IntoDatum>::type_oid(), self) }
+            }
+
+        }
+
+        impl ::pgserver::datum::FromDatum for #name {
+
+            fn is_compatible(type_oid : ::pgserver::datum::Oid) -> bool {
+                <::pgserver::row::Row as ::pgserver::datum::FromDatum>::is_compatible(type_oid)
+            }
+
+            unsafe fn from_datum(datum : ::pgserver::datum::Datum, is_null : bool) -> Option<Self> {
+                let row = <::pgserver::row::Row as ::pgserver::datum::FromDatum>::from_datum(datum, is_null)?;
+                Some(#name { #(#fields : row.get(#field_names)),* })
+            }
+
+        }
+    };
+    expanded.into()
+}
@@ -0,0 +1,336 @@
+use syn::{ItemImpl, ImplItem, Type, NestedMeta, Meta, Lit, Error};
+use super::types::{sql_type, snake_case};
+use super::function::{Function, Argument, Returns, Options};
+
+/// Support functions generated for an aggregate, which the CREATE AGGREGATE statement refers to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SupportFunction {
+    Transition,
+    Inverse,
+    Final,
+    Combine,
+    Serialize,
+    Deserialize
+}
+
+impl SupportFunction {
+
+    /// Suffix appended to the aggregate name to name the support function (which is also
+    /// the parameter of CREATE AGGREGATE it is passed to).
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            SupportFunction::Transition => "sfunc",
+            SupportFunction::Inverse => "minvfunc",
+            SupportFunction::Final => "finalfunc",
+            SupportFunction::Combine => "combinefunc",
+            SupportFunction::Serialize => "serialfunc",
+            SupportFunction::Deserialize => "deserialfunc"
+        }
+    }
+
+}
+
+/// Returns the type assigned to the associated type name at an impl block.
+pub fn assoc_type<'a>(item : &'a ItemImpl, name : &str) -> Option<&'a Type> {
+    item.items.iter().find_map(|it| match it {
+        ImplItem::Type(ty) if ty.ident == name => Some(&ty.ty),
+        _ => None
+    })
+}
+
+/// Whether the impl block defines the method name (instead of relying on the trait default).
+pub fn defines(item : &ItemImpl, name : &str) -> bool {
+    item.items.iter().any(|it| matches!(it, ImplItem::Method(m) if m.sig.ident == name) )
+}
+
+/// Types of the aggregated arguments: The elements of a tuple, or a single type. The unit type
+/// stands for no arguments (an aggregate over *).
+pub fn arg_types(args : &Type) -> Vec<&Type> {
+    match args {
+        Type::Tuple(tuple) => tuple.elems.iter().collect(),
+        Type::Paren(paren) => arg_types(&paren.elem),
+        Type::Group(group) => arg_types(&group.elem),
+        ty => vec![ty]
+    }
+}
+
+/// Signature of an aggregate implemented by a Rust type, with its internal state held by the
+/// support functions. The combine, serialize and deserialize functions are only declared if
+/// the impl block defines them, and are required to run the aggregate in parallel. Defining
+/// retract declares the inverse transition function, which enables the moving-aggregate mode
+/// (the same state, transition and final functions are used in both modes). Ordered-set
+/// aggregates (implementing OrderedSetAggregate) have direct arguments, which are passed to the
+/// final function, and take the aggregated arguments as WITHIN GROUP (ORDER BY ...).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    pub name : String,
+    pub direct_args : Option<Vec<String>>,
+    pub hypothetical : bool,
+    pub args : Vec<String>,
+    pub ret : String,
+    pub retract : bool,
+    pub combine : bool,
+    pub serialize : bool,
+    pub deserialize : bool,
+    pub options : Options
+}
+
+impl Aggregate {
+
+    /// Builds the aggregate declared by an impl Aggregate (or impl OrderedSetAggregate) block
+    /// annotated with #[pg_aggregate]. Besides the options accepted by #[pg_extern] (which apply
+    /// to the support functions and, for parallel_safe, to the aggregate), the attribute takes
+    /// name = "..." to name the aggregate (it is named after the type in snake case otherwise),
+    /// and hypothetical to declare an ordered-set aggregate as a hypothetical-set aggregate.
+    pub fn from_item(item : &ItemImpl, args : &[NestedMeta]) -> Result<Self, Error> {
+        let trait_name = match &item.trait_ {
+            Some((None, path, _)) => path.segments.last().map(|s| s.ident.to_string() ),
+            _ => None
+        };
+        let ordered = match trait_name.as_deref() {
+            Some("Aggregate") => false,
+            Some("OrderedSetAggregate") => true,
+            _ => return Err(Error::new_spanned(item, "Expected an impl Aggregate or impl OrderedSetAggregate block"))
+        };
+        if !item.generics.params.is_empty() {
+            return Err(Error::new_spanned(&item.generics, "Aggregates cannot be generic"));
+        }
+        let type_name = match &*item.self_ty {
+            Type::Path(path) => path.path.segments.last().unwrap().ident.to_string(),
+            other => return Err(Error::new_spanned(other, "Expected a named type"))
+        };
+
+        let mut name = snake_case(&type_name);
+        let mut hypothetical = false;
+        let mut fn_args = Vec::new();
+        for arg in args {
+            match arg {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("hypothetical") => {
+                    if !ordered {
+                        return Err(Error::new_spanned(path, "Only ordered-set aggregates can be hypothetical"));
+                    }
+                    hypothetical = true;
+                },
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("name") => {
+                    name = match &nv.lit {
+                        Lit::Str(s) => s.value(),
+                        other => return Err(Error::new_spanned(other, "Expected name = \"...\""))
+                    };
+                },
+                other => fn_args.push(other.clone())
+            }
+        }
+        let options = Options::from_args(&fn_args)?;
+        if options.cast.is_some() || options.columns.is_some() {
+            return Err(Error::new_spanned(item, "Aggregates do not accept the cast and columns options"));
+        }
+
+        let required = |ty_name : &str| assoc_type(item, ty_name).ok_or_else(|| {
+            Error::new_spanned(item, format!("Missing associated type {}", ty_name))
+        });
+        let direct_args = if ordered {
+            let mut direct_args = Vec::new();
+            for ty in arg_types(required("DirectArgs")?) {
+                direct_args.push(sql_type(ty)?);
+            }
+            Some(direct_args)
+        } else {
+            required("State")?;
+            None
+        };
+        let mut agg_args = Vec::new();
+        for ty in arg_types(required("Args")?) {
+            agg_args.push(sql_type(ty)?);
+        }
+        let ret = sql_type(required("Output")?)?;
+
+        if let Some(direct_args) = direct_args {
+            if agg_args.is_empty() {
+                return Err(Error::new_spanned(item, "Ordered-set aggregates require at least one aggregated argument"));
+            }
+            // The hypothetical row is given by the last direct arguments, one for each aggregated argument.
+            if hypothetical && !direct_args.ends_with(&agg_args) {
+                return Err(Error::new_spanned(item, "The last direct arguments of hypothetical-set aggregates must match the aggregated arguments"));
+            }
+            return Ok(Aggregate {
+                name,
+                direct_args : Some(direct_args),
+                hypothetical,
+                args : agg_args,
+                ret,
+                retract : false,
+                combine : false,
+                serialize : false,
+                deserialize : false,
+                options
+            });
+        }
+
+        let (combine, serialize, deserialize) = (defines(item, "combine"), defines(item, "serialize"), defines(item, "deserialize"));
+        if serialize != deserialize {
+            return Err(Error::new_spanned(item, "Aggregates must define both serialize and deserialize, or neither"));
+        }
+        if options.parallel_safe && !(combine && serialize) {
+            return Err(Error::new_spanned(item, "Parallel aggregates must define combine, serialize and deserialize"));
+        }
+        let retract = defines(item, "retract");
+        Ok(Aggregate { name, direct_args : None, hypothetical, args : agg_args, ret, retract, combine, serialize, deserialize, options })
+    }
+
+    /// Support functions declared for this aggregate.
+    pub fn support_functions(&self) -> Vec<SupportFunction> {
+        let mut funcs = vec![SupportFunction::Transition];
+        if self.retract {
+            funcs.push(SupportFunction::Inverse);
+        }
+        funcs.push(SupportFunction::Final);
+        if self.combine {
+            funcs.push(SupportFunction::Combine);
+        }
+        if self.serialize {
+            funcs.push(SupportFunction::Serialize);
+        }
+        if self.deserialize {
+            funcs.push(SupportFunction::Deserialize);
+        }
+        funcs
+    }
+
+    /// CREATE FUNCTION declaration of a support function. The internal state is created by the
+    /// transition function when it first receives a NULL state, so only the functions taking or
+    /// returning bytea are strict (the inverse transition must match the transition function).
+    pub fn support_function(&self, func : SupportFunction) -> Function {
+        let arg = |name : &str, ty : &str| Argument { name : String::from(name), ty : String::from(ty) };
+        let (args, ret, strict) = match func {
+            SupportFunction::Transition | SupportFunction::Inverse => {
+                let mut args = vec![arg("state", "internal")];
+                for (i, ty) in self.args.iter().enumerate() {
+                    args.push(arg(&format!("value{}", i + 1), ty));
+                }
+                (args, "internal", false)
+            },
+            SupportFunction::Final => {
+                let mut args = vec![arg("state", "internal")];
+                for (i, ty) in self.direct_args.iter().flatten().enumerate() {
+                    args.push(arg(&format!("direct{}", i + 1), ty));
+                }
+                (args, &self.ret[..], false)
+            },
+            SupportFunction::Combine => (vec![arg("state", "internal"), arg("other", "internal")], "internal", false),
+            SupportFunction::Serialize => (vec![arg("state", "internal")], "bytea", true),
+            SupportFunction::Deserialize => (vec![arg("bytes", "bytea"), arg("unused", "internal")], "internal", true)
+        };
+        Function {
+            name : format!("{}_{}", self.name, func.suffix()),
+            args,
+            ret : Returns::Value(String::from(ret)),
+            returns_set : false,
+            strict,
+            options : Options { cast : None, columns : None, ..self.options.clone() }
+        }
+    }
+
+    /// Returns the declarations of the support functions followed by the CREATE AGGREGATE
+    /// statement.
+    pub fn to_sql(&self) -> String {
+        let funcs = self.support_functions();
+        let mut sql = String::new();
+        for func in funcs.iter() {
+            sql += &self.support_function(*func).to_sql();
+            sql += "\n";
+        }
+        let args = match &self.direct_args {
+            Some(direct_args) if direct_args.is_empty() => format!("order by {}", self.args.join(", ")),
+            Some(direct_args) => format!("{} order by {}", direct_args.join(", "), self.args.join(", ")),
+            None if self.args.is_empty() => String::from("*"),
+            None => self.args.join(", ")
+        };
+        sql += &format!("create aggregate {}({}) (\n", self.name, args);
+        let mut params = Vec::new();
+        for func in funcs.iter() {
+            params.push(format!("    {} = {}", func.suffix(), self.support_function(*func).name));
+            if *func == SupportFunction::Transition {
+                params.push(String::from("    stype = internal"));
+            }
+        }
+        if self.retract {
+            let func_name = |func| self.support_function(func).name;
+            params.push(format!("    msfunc = {}", func_name(SupportFunction::Transition)));
+            params.push(String::from("    mstype = internal"));
+            params.push(format!("    mfinalfunc = {}", func_name(SupportFunction::Final)));
+        }
+        if self.hypothetical {
+            params.push(String::from("    hypothetical"));
+        }
+        if self.options.parallel_safe {
+            params.push(String::from("    parallel = safe"));
+        }
+        sql += &params.join(",\n");
+        sql += "\n);\n";
+        sql
+    }
+
+}
+
+#[test]
+fn aggregate_sql() {
+    let item : ItemImpl = syn::parse_str(r#"
+        impl Aggregate for GeoMean {
+            type State = (f64, i64);
+            type Args = f64;
+            type Output = Option<f64>;
+            fn init() -> Self::State { (0.0, 0) }
+            fn accum(state : &mut Self::State, v : f64) { state.0 += v.ln(); state.1 += 1; }
+            fn finalize(state : &Self::State) -> Option<f64> { None }
+            fn combine(state : &mut Self::State, other : &Self::State) { }
+        }
+    "#).unwrap();
+    let agg = Aggregate::from_item(&item, &[syn::parse_str("immutable").unwrap()]).unwrap();
+    assert_eq!(agg.support_functions(), [SupportFunction::Transition, SupportFunction::Final, SupportFunction::Combine]);
+    let sql = agg.to_sql();
+    assert!(sql.starts_with("create function geo_mean_sfunc(state internal, value1 double precision) returns internal as\n    'MODULE_PATHNAME', 'geo_mean_sfunc_wrapper'\nlanguage c immutable;\n"));
+    assert!(sql.ends_with(
+        "create aggregate geo_mean(double precision) (\n    sfunc = geo_mean_sfunc,\n    stype = internal,\n    \
+        finalfunc = geo_mean_finalfunc,\n    combinefunc = geo_mean_combinefunc\n);\n"
+    ));
+    let moving : ItemImpl = syn::parse_str(r#"
+        impl pgserver::aggregate::Aggregate for Sum {
+            type State = f64;
+            type Args = f64;
+            type Output = f64;
+            fn init() -> f64 { 0.0 }
+            fn accum(state : &mut f64, v : f64) { *state += v; }
+            fn retract(state : &mut f64, v : f64) -> bool { *state -= v; true }
+            fn finalize(state : &f64) -> f64 { *state }
+        }
+    "#).unwrap();
+    let sum = Aggregate::from_item(&moving, &[]).unwrap();
+    assert_eq!(sum.support_functions(), [SupportFunction::Transition, SupportFunction::Inverse, SupportFunction::Final]);
+    assert!(sum.to_sql().contains("create function sum_minvfunc(state internal, value1 double precision) returns internal as"));
+    assert!(sum.to_sql().ends_with(
+        "    minvfunc = sum_minvfunc,\n    finalfunc = sum_finalfunc,\n    msfunc = sum_sfunc,\n    \
+        mstype = internal,\n    mfinalfunc = sum_finalfunc\n);\n"
+    ));
+    let ordered : ItemImpl = syn::parse_str(r#"
+        impl OrderedSetAggregate for Rank {
+            type DirectArgs = (i32, f64);
+            type Args = f64;
+            type Output = i64;
+            fn finalize(direct : (i32, f64), sorted : &Sorted<f64>) -> i64 { 0 }
+        }
+    "#).unwrap();
+    let hypothetical : NestedMeta = syn::parse_str("hypothetical").unwrap();
+    let rank = Aggregate::from_item(&ordered, std::slice::from_ref(&hypothetical)).unwrap();
+    assert!(rank.to_sql().contains(
+        "create function rank_finalfunc(state internal, direct1 integer, direct2 double precision) returns bigint as"
+    ));
+    assert!(rank.to_sql().ends_with(
+        "create aggregate rank(integer, double precision order by double precision) (\n    sfunc = rank_sfunc,\n    \
+        stype = internal,\n    finalfunc = rank_finalfunc,\n    hypothetical\n);\n"
+    ));
+    assert!(Aggregate::from_item(&item, &[hypothetical]).is_err());
+    let parallel : NestedMeta = syn::parse_str("parallel_safe").unwrap();
+    assert!(Aggregate::from_item(&item, &[parallel]).is_err());
+    let renamed : NestedMeta = syn::parse_str("name = \"gmean\"").unwrap();
+    assert_eq!(Aggregate::from_item(&item, &[renamed]).unwrap().name, "gmean");
+}
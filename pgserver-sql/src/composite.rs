@@ -0,0 +1,68 @@
+use syn::{ItemStruct, Fields, Error};
+use super::function::Argument;
+use super::types::{sql_type, composite_name, is_reserved};
+
+/// Columns of a struct deriving PgRow or PgComposite, named after its fields and in the
+/// order they are declared.
+pub fn row_columns(item : &ItemStruct) -> Result<Vec<Argument>, Error> {
+    if !item.generics.params.is_empty() {
+        return Err(Error::new_spanned(&item.generics, "Row types cannot be generic"));
+    }
+    match &item.fields {
+        Fields::Named(fields) => {
+            let mut cols = Vec::new();
+            for field in fields.named.iter() {
+                let name = field.ident.as_ref().unwrap().to_string();
+                cols.push(Argument { name, ty : sql_type(&field.ty)? });
+            }
+            Ok(cols)
+        },
+        _ => Err(Error::new_spanned(item, "Row types must have named fields"))
+    }
+}
+
+/// Composite type declared for a struct deriving PgComposite. The type is named after the
+/// struct (see types::composite_name), and has one attribute per field. Structs named after
+/// built-in types (e.g. Point) are rejected, since their name would resolve to the built-in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Composite {
+    pub name : String,
+    pub columns : Vec<Argument>
+}
+
+impl Composite {
+
+    pub fn from_item(item : &ItemStruct) -> Result<Self, Error> {
+        let columns = row_columns(item)?;
+        if columns.is_empty() {
+            return Err(Error::new_spanned(item, "Composite types must have at least one field"));
+        }
+        let name = composite_name(&item.ident.to_string());
+        if is_reserved(&name) {
+            return Err(Error::new_spanned(&item.ident, format!("Composite type {} would clash with the built-in type {}", name, name)));
+        }
+        Ok(Composite { name, columns })
+    }
+
+    /// Returns the CREATE TYPE statement for this type.
+    pub fn to_sql(&self) -> String {
+        let cols : Vec<String> = self.columns.iter()
+            .map(|col| format!("    {} {}", col.name, col.ty) )
+            .collect();
+        format!("create type {} as (\n{}\n);\n", self.name, cols.join(",\n"))
+    }
+
+}
+
+#[test]
+fn composite_sql() {
+    let item : ItemStruct = syn::parse_str("struct GeoPoint { lat : f64, label : Option<Text> }").unwrap();
+    assert_eq!(
+        Composite::from_item(&item).unwrap().to_sql(),
+        "create type geo_point as (\n    lat double precision,\n    label text\n);\n"
+    );
+    let item : ItemStruct = syn::parse_str("struct Pair(i32, i32);").unwrap();
+    assert!(Composite::from_item(&item).is_err());
+    let item : ItemStruct = syn::parse_str("struct Point { x : f64, y : f64 }").unwrap();
+    assert!(Composite::from_item(&item).is_err());
+}
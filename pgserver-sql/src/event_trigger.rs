@@ -0,0 +1,92 @@
+use syn::{ItemFn, NestedMeta, Meta, Lit, Error};
+use super::function::{Function, Returns};
+
+/// Events an event trigger can fire on (as named at CREATE EVENT TRIGGER), on every supported
+/// server version (login is left out, since it requires PostgreSQL 17).
+const EVENTS : [&str; 4] = ["ddl_command_start", "ddl_command_end", "sql_drop", "table_rewrite"];
+
+/// An event trigger function, and optionally the event trigger that calls it. The event trigger
+/// is named after the function, and fires on the given event for the given command tags (or for
+/// any command, if no tags are given).
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventTrigger {
+    pub function : Function,
+    pub event : Option<String>,
+    pub tags : Vec<String>
+}
+
+impl EventTrigger {
+
+    /// Builds the event trigger declared by a function annotated with #[pg_event_trigger]. Besides
+    /// the options accepted by #[pg_trigger], the attribute takes event = "..." to create the event
+    /// trigger, and tags("CREATE TABLE", ...) to restrict it to the given command tags.
+    pub fn from_item(item : &ItemFn, args : &[NestedMeta]) -> Result<Self, Error> {
+        let mut event = None;
+        let mut tags = Vec::new();
+        let mut fn_args = Vec::new();
+        for arg in args {
+            match arg {
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("event") => {
+                    event = match &nv.lit {
+                        Lit::Str(s) if EVENTS.contains(&&s.value()[..]) => Some(s.value()),
+                        other => return Err(Error::new_spanned(
+                            other,
+                            format!("Expected event = \"...\", with one of the events {}", EVENTS.join(", "))
+                        ))
+                    };
+                },
+                NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("tags") => {
+                    for tag in list.nested.iter() {
+                        match tag {
+                            NestedMeta::Lit(Lit::Str(s)) => tags.push(s.value().to_uppercase()),
+                            other => return Err(Error::new_spanned(other, "Expected a command tag (such as \"CREATE TABLE\")"))
+                        }
+                    }
+                },
+                other => fn_args.push(other.clone())
+            }
+        }
+        if !tags.is_empty() && event.is_none() {
+            return Err(Error::new_spanned(&item.sig, "Command tags require the event option"));
+        }
+        let mut function = Function::from_trigger(item, &fn_args)?;
+        function.ret = Returns::Value(String::from("event_trigger"));
+        Ok(EventTrigger { function, event, tags })
+    }
+
+    /// Returns the declaration of the function, followed by the CREATE EVENT TRIGGER statement
+    /// if an event was given.
+    pub fn to_sql(&self) -> String {
+        let mut sql = self.function.to_sql();
+        if let Some(event) = &self.event {
+            sql += &format!("\ncreate event trigger {} on {}\n", self.function.name, event);
+            if !self.tags.is_empty() {
+                let tags : Vec<String> = self.tags.iter().map(|tag| format!("'{}'", tag.replace('\'', "''")) ).collect();
+                sql += &format!("    when tag in ({})\n", tags.join(", "));
+            }
+            sql += &format!("    execute function {}();\n", self.function.name);
+        }
+        sql
+    }
+
+}
+
+#[test]
+fn event_trigger_sql() {
+    let item : ItemFn = syn::parse_str("fn log_ddl(trigger : &EventTrigger) { }").unwrap();
+    let args : syn::AttributeArgs = vec![
+        syn::parse_str("event = \"ddl_command_end\"").unwrap(),
+        syn::parse_str("tags(\"create table\", \"ALTER TABLE\")").unwrap()
+    ];
+    let trigger = EventTrigger::from_item(&item, &args).unwrap();
+    assert_eq!(
+        trigger.to_sql(),
+        "create function log_ddl() returns event_trigger as\n    'MODULE_PATHNAME', 'log_ddl_wrapper'\nlanguage c;\n\n\
+        create event trigger log_ddl on ddl_command_end\n    when tag in ('CREATE TABLE', 'ALTER TABLE')\n    \
+        execute function log_ddl();\n"
+    );
+    assert!(!EventTrigger::from_item(&item, &[]).unwrap().to_sql().contains("create event trigger"));
+    assert!(EventTrigger::from_item(&item, &[syn::parse_str("event = \"commit\"").unwrap()]).is_err());
+    assert!(EventTrigger::from_item(&item, &[syn::parse_str("event = \"login\"").unwrap()]).is_err());
+    assert!(EventTrigger::from_item(&item, &[syn::parse_str("tags(\"DROP TABLE\")").unwrap()]).is_err());
+}
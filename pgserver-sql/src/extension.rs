@@ -0,0 +1,385 @@
+use syn::{Item, ItemFn, ItemImpl, ItemStruct, Attribute, Meta, NestedMeta, Error, FnArg, ReturnType};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use super::function::{Function, Argument};
+use super::composite::{Composite, row_columns};
+use super::aggregate::{Aggregate, assoc_type};
+use super::event_trigger::EventTrigger;
+use super::types::{check_structs, struct_name, option_inner, iterator_item};
+
+/// An SQL object declared by the extension script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlItem {
+    Function(Function),
+    Composite(Composite),
+    Aggregate(Aggregate),
+    EventTrigger(EventTrigger)
+}
+
+impl SqlItem {
+
+    pub fn to_sql(&self) -> String {
+        match self {
+            SqlItem::Function(f) => f.to_sql(),
+            SqlItem::Composite(c) => c.to_sql(),
+            SqlItem::Aggregate(a) => a.to_sql(),
+            SqlItem::EventTrigger(t) => t.to_sql()
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            SqlItem::Function(f) => &f.name,
+            SqlItem::Composite(c) => &c.name,
+            SqlItem::Aggregate(a) => &a.name,
+            SqlItem::EventTrigger(t) => &t.function.name
+        }
+    }
+
+}
+
+/// Returns the arguments of the attribute if its last path segment is name (so both
+/// #[pg_extern] and #[pgserver::pg_extern] are recognized).
+fn attr_args(attr : &Attribute, name : &str) -> Result<Option<Vec<NestedMeta>>, Error> {
+    match attr.path.segments.last() {
+        Some(seg) if seg.ident == name => { },
+        _ => return Ok(None)
+    }
+    match attr.parse_meta()? {
+        Meta::Path(_) => Ok(Some(Vec::new())),
+        Meta::List(list) => Ok(Some(list.nested.into_iter().collect())),
+        meta @ Meta::NameValue(_) => Err(Error::new_spanned(meta, format!("Invalid {} attribute", name)))
+    }
+}
+
+/// Whether the item has #[derive(..., name, ...)] among its attributes.
+fn derives(attrs : &[Attribute], name : &str) -> Result<bool, Error> {
+    for attr in attrs {
+        if let Some(args) = attr_args(attr, "derive")? {
+            let found = args.iter().any(|arg| match arg {
+                NestedMeta::Meta(Meta::Path(path)) => path.segments.last().map(|s| s.ident == name ).unwrap_or(false),
+                _ => false
+            });
+            if found {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// The #[cfg(...)] attribute among attrs, if any.
+fn cfg(attrs : &[Attribute]) -> Option<&Attribute> {
+    attrs.iter().find(|attr| attr.path.is_ident("cfg") )
+}
+
+/// Whether the attribute is #[cfg(test)], which is never set when the extension is built.
+fn cfg_test(attr : &Attribute) -> bool {
+    match attr.parse_meta() {
+        Ok(Meta::List(list)) if list.nested.len() == 1 => {
+            matches!(&list.nested[0], NestedMeta::Meta(Meta::Path(path)) if path.is_ident("test"))
+        },
+        _ => false
+    }
+}
+
+/// SQL items and row types found while scanning the crate. The crate is scanned twice: First for
+/// the names of the structs deriving PgRow and PgComposite (which might be declared anywhere in
+/// the crate), and then for the items, whose types can only refer to these structs.
+#[derive(Default)]
+struct Scan {
+    items : Vec<SqlItem>,
+    rows : HashMap<String, Vec<Argument>>,
+    row_structs : HashSet<String>,
+    composites : HashSet<String>
+}
+
+impl Scan {
+
+    /// Collects the names of the structs deriving PgRow and PgComposite (skipping the ones under
+    /// #[cfg(test)], as items does).
+    fn structs(&mut self, items : &[Item]) -> Result<(), Error> {
+        for item in items {
+            match item {
+                Item::Struct(item_struct) if cfg(&item_struct.attrs).map(cfg_test) != Some(true) => {
+                    if derives(&item_struct.attrs, "PgRow")? {
+                        self.row_structs.insert(item_struct.ident.to_string());
+                    } else if derives(&item_struct.attrs, "PgComposite")? {
+                        self.composites.insert(item_struct.ident.to_string());
+                    }
+                },
+                Item::Mod(item_mod) if cfg(&item_mod.attrs).map(cfg_test) != Some(true) => {
+                    if let Some((_, items)) = &item_mod.content {
+                        self.structs(items)?;
+                    }
+                },
+                _ => { }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the structs used by an exported function: Its result (or the items of its
+    /// iterator) might be a row or a composite type, and anything else must be a composite type.
+    fn check_fn(&self, item_fn : &ItemFn) -> Result<(), Error> {
+        for input in item_fn.sig.inputs.iter() {
+            if let FnArg::Typed(pat_ty) = input {
+                check_structs(&pat_ty.ty, &self.composites)?;
+            }
+        }
+        if let ReturnType::Type(_, ty) = &item_fn.sig.output {
+            let item = iterator_item(ty).unwrap_or(ty);
+            let item = option_inner(item).unwrap_or(item);
+            match struct_name(item) {
+                Some(name) if self.row_structs.contains(&name) => { },
+                Some(name) if !self.composites.contains(&name) => {
+                    return Err(Error::new_spanned(
+                        item,
+                        format!("Type {} has no PostgreSQL equivalent (structs must derive PgRow or PgComposite)", name)
+                    ));
+                },
+                _ => check_structs(ty, &self.composites)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the structs used by the fields of a row or composite type.
+    fn check_struct(&self, item_struct : &ItemStruct) -> Result<(), Error> {
+        item_struct.fields.iter().try_for_each(|field| check_structs(&field.ty, &self.composites) )
+    }
+
+    /// Checks the structs used by the arguments and result of an aggregate.
+    fn check_aggregate(&self, item_impl : &ItemImpl) -> Result<(), Error> {
+        ["Args", "DirectArgs", "Output"].iter()
+            .filter_map(|name| assoc_type(item_impl, name) )
+            .try_for_each(|ty| check_structs(ty, &self.composites) )
+    }
+
+    /// Scans the items, which are conditionally compiled if gate is the #[cfg] attribute of an
+    /// enclosing module. Items under #[cfg(test)] are skipped. Other configurations cannot be
+    /// evaluated here, so exporting a conditionally compiled item is an error (the script would
+    /// declare it even when it is not compiled into the library).
+    fn items(&mut self, items : &[Item], gate : Option<&Attribute>) -> Result<(), Error> {
+        for item in items {
+            let attrs = match item {
+                Item::Fn(item_fn) => &item_fn.attrs,
+                Item::Struct(item_struct) => &item_struct.attrs,
+                Item::Impl(item_impl) => &item_impl.attrs,
+                Item::Mod(item_mod) => &item_mod.attrs,
+                _ => continue
+            };
+            let gate = match cfg(attrs) {
+                Some(attr) if cfg_test(attr) => continue,
+                Some(attr) => Some(attr),
+                None => gate
+            };
+            let found = self.items.len() + self.rows.len();
+            match item {
+                Item::Fn(item_fn) => {
+                    for attr in item_fn.attrs.iter() {
+                        if let Some(args) = attr_args(attr, "pg_extern")? {
+                            self.check_fn(item_fn)?;
+                            self.items.push(SqlItem::Function(Function::from_item(item_fn, &args)?));
+                        }
+                        if let Some(args) = attr_args(attr, "pg_trigger")? {
+                            self.items.push(SqlItem::Function(Function::from_trigger(item_fn, &args)?));
+                        }
+                        if let Some(args) = attr_args(attr, "pg_event_trigger")? {
+                            self.items.push(SqlItem::EventTrigger(EventTrigger::from_item(item_fn, &args)?));
+                        }
+                    }
+                },
+                Item::Struct(item_struct) if derives(&item_struct.attrs, "PgRow")? => {
+                    self.check_struct(item_struct)?;
+                    self.rows.insert(item_struct.ident.to_string(), row_columns(item_struct)?);
+                },
+                Item::Struct(item_struct) if derives(&item_struct.attrs, "PgComposite")? => {
+                    self.check_struct(item_struct)?;
+                    self.items.push(SqlItem::Composite(Composite::from_item(item_struct)?));
+                },
+                Item::Impl(item_impl) => {
+                    for attr in item_impl.attrs.iter() {
+                        if let Some(args) = attr_args(attr, "pg_aggregate")? {
+                            self.check_aggregate(item_impl)?;
+                            self.items.push(SqlItem::Aggregate(Aggregate::from_item(item_impl, &args)?));
+                        }
+                    }
+                },
+                Item::Mod(item_mod) => {
+                    if let Some((_, items)) = &item_mod.content {
+                        self.items(items, gate)?;
+                    }
+                },
+                _ => { }
+            }
+            if let Some(attr) = gate {
+                if self.items.len() + self.rows.len() > found {
+                    return Err(Error::new_spanned(attr, "Items exported to SQL cannot be conditionally compiled"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the .rs files under dir, in lexicographical order of their paths.
+    fn parse_dir(dir : &Path, files : &mut Vec<(PathBuf, syn::File)>) -> Result<(), String> {
+        let mut entries : Vec<_> = fs::read_dir(dir)
+            .map_err(|e| format!("Unable to read directory {}: {}", dir.display(), e) )?
+            .filter_map(|e| e.ok() )
+            .map(|e| e.path() )
+            .collect();
+        entries.sort();
+        for path in entries {
+            if path.is_dir() {
+                Self::parse_dir(&path, files)?;
+            } else if path.extension().and_then(|ext| ext.to_str() ) == Some("rs") {
+                let src = fs::read_to_string(&path)
+                    .map_err(|e| format!("Unable to read {}: {}", path.display(), e) )?;
+                let file = syn::parse_file(&src)
+                    .map_err(|e| format!("Unable to parse {}: {}", path.display(), e) )?;
+                files.push((path, file));
+            }
+        }
+        Ok(())
+    }
+
+    fn files(&mut self, files : &[(PathBuf, syn::File)]) -> Result<(), String> {
+        let located = |path : &Path, e : Error| {
+            let start = e.span().start();
+            format!("{}:{}:{}: {}", path.display(), start.line, start.column, e)
+        };
+        for (path, file) in files {
+            self.structs(&file.items).map_err(|e| located(path, e) )?;
+        }
+        for (path, file) in files {
+            self.items(&file.items, None).map_err(|e| located(path, e) )?;
+        }
+        Ok(())
+    }
+
+    /// Resolves the structs returned by functions, once all files are scanned. Composite types are
+    /// moved before the functions (keeping their relative order), since functions might use them.
+    fn finish(mut self) -> Result<Vec<SqlItem>, String> {
+        for item in self.items.iter_mut() {
+            match item {
+                SqlItem::Function(f) => f.resolve_rows(&self.rows, &self.composites)?,
+                SqlItem::Composite(_) | SqlItem::Aggregate(_) | SqlItem::EventTrigger(_) => { }
+            }
+        }
+        let (mut items, functions) : (Vec<_>, Vec<_>) = self.items.into_iter()
+            .partition(|item| matches!(item, SqlItem::Composite(_)) );
+        items.extend(functions);
+        Ok(items)
+    }
+
+}
+
+/// Collects the SQL items declared by the annotated items of a parsed source file (including
+/// the ones declared at inline modules), in the order they appear.
+pub fn scan_file(file : &syn::File) -> Result<Vec<SqlItem>, String> {
+    let mut scan = Scan::default();
+    scan.structs(&file.items).map_err(|e| e.to_string() )?;
+    scan.items(&file.items, None).map_err(|e| e.to_string() )?;
+    scan.finish()
+}
+
+/// Collects the SQL items declared at all .rs files under dir. Files are visited in
+/// lexicographical order of their paths, so the output is stable across runs.
+pub fn scan_dir(dir : &Path) -> Result<Vec<SqlItem>, String> {
+    let mut files = Vec::new();
+    Scan::parse_dir(dir, &mut files)?;
+    let mut scan = Scan::default();
+    scan.files(&files)?;
+    scan.finish()
+}
+
+/// Renders the extension script for the given items, appending the hand-written SQL
+/// fragments after the generated declarations.
+pub fn render_script(ext_name : &str, items : &[SqlItem], fragments : &[String]) -> String {
+    let mut script = String::new();
+    script += "-- complain if script is sourced in psql, rather than via CREATE EXTENSION\n";
+    script += &format!("\\echo Use \"CREATE EXTENSION {}\" to load this file. \\quit\n", ext_name);
+    for item in items {
+        script += "\n";
+        script += &item.to_sql();
+    }
+    for frag in fragments {
+        script += "\n";
+        script += frag;
+        if !frag.ends_with('\n') {
+            script += "\n";
+        }
+    }
+    script
+}
+
+#[test]
+fn scan_module() {
+    let file : syn::File = syn::parse_str(r#"
+        #[pg_extern]
+        fn one() -> i32 { 1 }
+
+        fn not_exported() -> i32 { 2 }
+
+        mod inner {
+            #[pgserver::pg_extern(immutable)]
+            fn two() -> i64 { 2 }
+
+            #[pg_extern]
+            fn point() -> Point { Point { x : 1.0, y : None } }
+        }
+
+        #[derive(Clone, PgRow)]
+        struct Point {
+            x : f64,
+            y : Option<f64>
+        }
+
+        #[pg_extern]
+        fn line(a : GeoLine) -> impl Iterator<Item = GeoLine> { std::iter::once(a) }
+
+        #[derive(pgserver::PgComposite)]
+        struct GeoLine {
+            slope : f64,
+            intercept : f64
+        }
+    "#).unwrap();
+    let items = scan_file(&file).unwrap();
+    let names : Vec<_> = items.iter().map(|it| it.name() ).collect();
+    assert_eq!(names, ["geo_line", "one", "two", "point", "line"]);
+    assert!(items[0].to_sql().starts_with("create type geo_line as ("));
+    assert!(items[3].to_sql().starts_with("create function point(out x double precision, out y double precision) returns record as"));
+    assert!(items[4].to_sql().starts_with("create function line(a geo_line) returns setof geo_line as"));
+    let file : syn::File = syn::parse_str("#[pg_extern] fn ord(a : Array<Ordering>) -> i32 { 0 }").unwrap();
+    assert!(scan_file(&file).unwrap_err().starts_with("Type Ordering has no PostgreSQL equivalent"));
+    let file : syn::File = syn::parse_str("type Score = i32; #[pg_extern] fn best(a : Score) -> Score { a }").unwrap();
+    assert!(scan_file(&file).unwrap_err().starts_with("Type Score has no PostgreSQL equivalent"));
+    let script = render_script("ext", &items, &[String::from("create table t (a integer);")]);
+    assert!(script.contains("\\echo Use \"CREATE EXTENSION ext\""));
+    assert!(script.ends_with("create table t (a integer);\n"));
+}
+
+#[test]
+fn scan_cfg() {
+    let file : syn::File = syn::parse_str(r#"
+        #[pg_extern]
+        fn one() -> i32 { 1 }
+
+        #[cfg(unix)]
+        fn helper() -> i32 { 2 }
+
+        #[cfg(test)]
+        mod tests {
+            #[pg_extern]
+            fn two() -> i32 { 2 }
+        }
+    "#).unwrap();
+    let items = scan_file(&file).unwrap();
+    assert_eq!(items.len(), 1);
+    for src in ["#[cfg(feature = \"x\")] #[pg_extern] fn two() -> i32 { 2 }", "#[cfg(unix)] mod m { #[pg_extern] fn two() -> i32 { 2 } }"].iter() {
+        let file : syn::File = syn::parse_str(src).unwrap();
+        assert!(scan_file(&file).unwrap_err().contains("cannot be conditionally compiled"));
+    }
+}
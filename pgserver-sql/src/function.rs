@@ -0,0 +1,394 @@
+use syn::{ItemFn, FnArg, Pat, ReturnType, NestedMeta, Meta, Lit, Error, Type};
+use std::collections::{HashMap, HashSet};
+use super::types::{sql_type, option_inner, iterator_item, struct_name, composite_name};
+
+/// Volatility category of a function, as declared at the CREATE FUNCTION statement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Volatility {
+    Immutable,
+    Stable,
+    Volatile
+}
+
+/// Context in which a cast declared with the function can be invoked implicitly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CastContext {
+    Explicit,
+    Assignment,
+    Implicit
+}
+
+/// Options accepted by the #[pg_extern] attribute:
+///
+/// ```rust,ignore
+/// #[pg_extern(immutable, parallel_safe)]
+/// fn add(a : i32, b : i32) -> i32 {
+///     a + b
+/// }
+/// ```
+///
+/// A single-argument function can also be declared as a cast from its argument type to its
+/// return type with cast (explicit only), cast = "assignment" or cast = "implicit". Functions
+/// returning tuples name the result columns with columns(a, b, ...) (the columns are named
+/// column1, column2, etc. otherwise).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub volatility : Volatility,
+    pub parallel_safe : bool,
+    pub cast : Option<CastContext>,
+    pub columns : Option<Vec<String>>
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { volatility : Volatility::Volatile, parallel_safe : false, cast : None, columns : None }
+    }
+}
+
+impl Options {
+
+    /// Parses the comma-separated arguments given to the attribute.
+    pub fn from_args(args : &[NestedMeta]) -> Result<Self, Error> {
+        let mut opts = Options::default();
+        for arg in args {
+            match arg {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("immutable") => {
+                    opts.volatility = Volatility::Immutable;
+                },
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("stable") => {
+                    opts.volatility = Volatility::Stable;
+                },
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("volatile") => {
+                    opts.volatility = Volatility::Volatile;
+                },
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("parallel_safe") => {
+                    opts.parallel_safe = true;
+                },
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("cast") => {
+                    opts.cast = Some(CastContext::Explicit);
+                },
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("cast") => {
+                    opts.cast = match &nv.lit {
+                        Lit::Str(s) if s.value() == "explicit" => Some(CastContext::Explicit),
+                        Lit::Str(s) if s.value() == "assignment" => Some(CastContext::Assignment),
+                        Lit::Str(s) if s.value() == "implicit" => Some(CastContext::Implicit),
+                        _ => return Err(Error::new_spanned(
+                            &nv.lit,
+                            "Expected cast = \"explicit\", \"assignment\" or \"implicit\""
+                        ))
+                    };
+                },
+                NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("columns") => {
+                    let mut columns = Vec::new();
+                    for col in list.nested.iter() {
+                        match col {
+                            NestedMeta::Meta(Meta::Path(path)) if path.get_ident().is_some() => {
+                                columns.push(path.get_ident().unwrap().to_string());
+                            },
+                            _ => return Err(Error::new_spanned(col, "Expected a column name"))
+                        }
+                    }
+                    opts.columns = Some(columns);
+                },
+                _ => return Err(Error::new_spanned(arg, "Unknown pg_extern option"))
+            }
+        }
+        Ok(opts)
+    }
+
+}
+
+/// A function argument, carrying its Rust name and its SQL type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Argument {
+    pub name : String,
+    pub ty : String
+}
+
+/// What a function returns (or, for set-returning functions, what each of its items is).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Returns {
+
+    /// A value of the given SQL type (void for functions returning ()).
+    Value(String),
+
+    /// A row with the given columns, built from a tuple or from a struct deriving PgRow.
+    Row(Vec<Argument>),
+
+    /// A row built from the struct with the given name, which is only known to be an anonymous
+    /// row (PgRow) or a composite type (PgComposite) once the struct definition is found (see
+    /// Function::resolve_rows). Unresolved rows are declared as record.
+    Struct(String)
+
+}
+
+/// Signature of a Rust function exported to PostgreSQL. Functions are declared strict (the
+/// server returns NULL without calling them when any argument is NULL) unless an argument
+/// is an Option. Functions returning impl Iterator<Item = T> are declared as returning
+/// setof T, and ret describes T.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name : String,
+    pub args : Vec<Argument>,
+    pub ret : Returns,
+    pub returns_set : bool,
+    pub strict : bool,
+    pub options : Options
+}
+
+impl Function {
+
+    /// Builds the SQL signature of a function annotated with #[pg_extern]. Fails if the function
+    /// is generic, takes a receiver or uses a type that has no SQL equivalent.
+    pub fn from_item(item : &ItemFn, args : &[NestedMeta]) -> Result<Self, Error> {
+        let sig = &item.sig;
+        if !sig.generics.params.is_empty() {
+            return Err(Error::new_spanned(&sig.generics, "Functions exported to PostgreSQL cannot be generic"));
+        }
+        if let Some(asyncness) = &sig.asyncness {
+            return Err(Error::new_spanned(asyncness, "Functions exported to PostgreSQL cannot be async"));
+        }
+        if let Some(variadic) = &sig.variadic {
+            return Err(Error::new_spanned(variadic, "Functions exported to PostgreSQL cannot be variadic"));
+        }
+        let mut fn_args = Vec::new();
+        let mut strict = true;
+        for input in sig.inputs.iter() {
+            match input {
+                FnArg::Typed(pat_ty) => {
+                    let name = match &*pat_ty.pat {
+                        Pat::Ident(pat_id) => pat_id.ident.to_string(),
+                        other => return Err(Error::new_spanned(other, "Expected a named argument"))
+                    };
+                    if option_inner(&pat_ty.ty).is_some() {
+                        strict = false;
+                    }
+                    fn_args.push(Argument { name, ty : sql_type(&pat_ty.ty)? });
+                },
+                FnArg::Receiver(recv) => {
+                    return Err(Error::new_spanned(recv, "Functions exported to PostgreSQL cannot take self"));
+                }
+            }
+        }
+        let options = Options::from_args(args)?;
+        let (ret, returns_set) = match &sig.output {
+            ReturnType::Default => (Returns::Value(String::from("void")), false),
+            ReturnType::Type(_, ty) => match iterator_item(ty) {
+                Some(item) => (returns(item, &options)?, true),
+                None => (returns(ty, &options)?, false)
+            }
+        };
+        let returns_value = match &ret {
+            Returns::Value(ty) => ty != "void" && !returns_set,
+            _ => false
+        };
+        if options.cast.is_some() && (fn_args.len() != 1 || !returns_value) {
+            return Err(Error::new_spanned(sig, "Casts must take a single argument and return a single value"));
+        }
+        Ok(Function {
+            name : sig.ident.to_string(),
+            args : fn_args,
+            ret,
+            returns_set,
+            strict,
+            options
+        })
+    }
+
+    /// Builds the declaration of a trigger function annotated with #[pg_trigger]. The function
+    /// takes the trigger information (&Trigger) as its single argument, so it is declared
+    /// without arguments and returning trigger. It accepts the options of #[pg_extern], except
+    /// for cast and columns. Event trigger functions are declared the same way, returning
+    /// event_trigger instead (see EventTrigger).
+    pub fn from_trigger(item : &ItemFn, args : &[NestedMeta]) -> Result<Self, Error> {
+        let sig = &item.sig;
+        if !sig.generics.params.is_empty() || sig.asyncness.is_some() || sig.inputs.len() != 1 {
+            return Err(Error::new_spanned(sig, "Trigger functions must take the trigger information as their single argument"));
+        }
+        let options = Options::from_args(args)?;
+        if options.cast.is_some() || options.columns.is_some() {
+            return Err(Error::new_spanned(sig, "Trigger functions do not accept the cast and columns options"));
+        }
+        Ok(Function {
+            name : sig.ident.to_string(),
+            args : Vec::new(),
+            ret : Returns::Value(String::from("trigger")),
+            returns_set : false,
+            strict : false,
+            options
+        })
+    }
+
+    /// Whether the function returns rows (or sets of rows) rather than scalar values.
+    pub fn returns_row(&self) -> bool {
+        !matches!(self.ret, Returns::Value(_))
+    }
+
+    /// Resolves a row returned as a struct, given the columns of all structs deriving PgRow
+    /// and the names of all structs deriving PgComposite. Rows are replaced by the columns of
+    /// their struct, and composites by their type. Fails if the struct is not among either.
+    pub fn resolve_rows(
+        &mut self,
+        rows : &HashMap<String, Vec<Argument>>,
+        composites : &HashSet<String>
+    ) -> Result<(), String> {
+        if let Returns::Struct(name) = &self.ret {
+            if let Some(columns) = rows.get(name) {
+                if columns.len() < 2 {
+                    return Err(format!("Type {} returned by {} must have at least two fields", name, self.name));
+                }
+                self.ret = Returns::Row(columns.clone());
+            } else if composites.contains(name) {
+                self.ret = Returns::Value(composite_name(name));
+            } else {
+                return Err(format!("Type {} returned by {} does not derive PgRow or PgComposite", name, self.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of the V1 entry point generated for this function (its pg_finfo record is
+    /// named pg_finfo_[symbol]).
+    pub fn symbol(&self) -> String {
+        format!("{}_wrapper", self.name)
+    }
+
+    /// Returns the CREATE FUNCTION statement for this function, followed by the
+    /// CREATE CAST statement if the function was declared as a cast.
+    pub fn to_sql(&self) -> String {
+        let mut args : Vec<String> = self.args.iter()
+            .map(|arg| format!("{} {}", arg.name, arg.ty) )
+            .collect();
+        let setof = if self.returns_set { "setof " } else { "" };
+
+        // Single rows are declared through OUT arguments, and sets of rows as tables.
+        let ret = match &self.ret {
+            Returns::Value(ty) => format!("{}{}", setof, ty),
+            Returns::Row(cols) if self.returns_set => {
+                let cols : Vec<String> = cols.iter().map(|col| format!("{} {}", col.name, col.ty) ).collect();
+                format!("table ({})", cols.join(", "))
+            },
+            Returns::Row(cols) => {
+                args.extend(cols.iter().map(|col| format!("out {} {}", col.name, col.ty) ));
+                String::from("record")
+            },
+            Returns::Struct(_) => format!("{}record", setof)
+        };
+        let mut sql = format!("create function {}({}) returns {} as\n", self.name, args.join(", "), ret);
+        sql += &format!("    'MODULE_PATHNAME', '{}'\n", self.symbol());
+        sql += "language c";
+        if self.strict {
+            sql += " strict";
+        }
+        match self.options.volatility {
+            Volatility::Immutable => sql += " immutable",
+            Volatility::Stable => sql += " stable",
+            Volatility::Volatile => { }
+        }
+        if self.options.parallel_safe {
+            sql += " parallel safe";
+        }
+        sql += ";\n";
+        if let Some(ctx) = self.options.cast {
+            let source = &self.args[0].ty;
+            let target = match &self.ret {
+                Returns::Value(ty) => ty,
+                _ => unreachable!()
+            };
+            sql += &format!("\ncreate cast ({} as {}) with function {}({})", source, target, self.name, source);
+            match ctx {
+                CastContext::Explicit => { },
+                CastContext::Assignment => sql += " as assignment",
+                CastContext::Implicit => sql += " as implicit"
+            }
+            sql += ";\n";
+        }
+        sql
+    }
+
+}
+
+/// Determines what a function returns from its return type (or the item type of its iterator).
+/// Tuples are rows, and user-defined structs are either rows or composite types.
+fn returns(ty : &Type, options : &Options) -> Result<Returns, Error> {
+    let ty = option_inner(ty).unwrap_or(ty);
+    let ret = match ty {
+        Type::Tuple(tuple) if !tuple.elems.is_empty() => {
+            if tuple.elems.len() < 2 {
+                return Err(Error::new_spanned(ty, "Rows must have at least two columns (return the value itself instead)"));
+            }
+            let names : Vec<String> = match &options.columns {
+                Some(names) if names.len() == tuple.elems.len() => names.clone(),
+                Some(_) => return Err(Error::new_spanned(ty, "Expected one column name for each tuple element")),
+                None => (1..=tuple.elems.len()).map(|i| format!("column{}", i) ).collect()
+            };
+            let mut cols = Vec::new();
+            for (name, elem) in names.into_iter().zip(tuple.elems.iter()) {
+                cols.push(Argument { name, ty : sql_type(elem)? });
+            }
+            return Ok(Returns::Row(cols));
+        },
+        _ => match struct_name(ty) {
+            Some(name) => Returns::Struct(name),
+            None => Returns::Value(sql_type(ty)?)
+        }
+    };
+    if options.columns.is_some() {
+        return Err(Error::new_spanned(ty, "Column names can only be given to functions returning tuples"));
+    }
+    Ok(ret)
+}
+
+#[test]
+fn trigger_sql() {
+    let item : ItemFn = syn::parse_str("fn stamp(trigger : &Trigger) -> Option<Row> { trigger.new_row() }").unwrap();
+    let f = Function::from_trigger(&item, &[]).unwrap();
+    assert_eq!(f.to_sql(), "create function stamp() returns trigger as\n    'MODULE_PATHNAME', 'stamp_wrapper'\nlanguage c;\n");
+    let item : ItemFn = syn::parse_str("fn stamp(a : i32, b : i32) -> Option<Row> { None }").unwrap();
+    assert!(Function::from_trigger(&item, &[]).is_err());
+}
+
+#[test]
+fn function_sql() {
+    let item : ItemFn = syn::parse_str("fn add(a : i32, b : i32) -> i32 { a + b }").unwrap();
+    let opts : syn::AttributeArgs = vec![syn::parse_str("immutable").unwrap()];
+    let f = Function::from_item(&item, &opts).unwrap();
+    assert_eq!(
+        f.to_sql(),
+        "create function add(a integer, b integer) returns integer as\n    \
+        'MODULE_PATHNAME', 'add_wrapper'\nlanguage c strict immutable;\n"
+    );
+    let item : ItemFn = syn::parse_str("fn to_text(b : Bytea) -> Text { b.try_into().unwrap() }").unwrap();
+    let opts : syn::AttributeArgs = vec![syn::parse_str("cast = \"assignment\"").unwrap()];
+    let f = Function::from_item(&item, &opts).unwrap();
+    assert!(f.to_sql().ends_with("create cast (bytea as text) with function to_text(bytea) as assignment;\n"));
+    let item : ItemFn = syn::parse_str("fn coalesce(a : Option<i32>, b : i32) -> Option<i32> { a }").unwrap();
+    let f = Function::from_item(&item, &[]).unwrap();
+    assert!(!f.strict);
+    assert!(f.to_sql().starts_with("create function coalesce(a integer, b integer) returns integer as"));
+    assert!(f.to_sql().ends_with("language c;\n"));
+    let item : ItemFn = syn::parse_str("fn series(n : i64) -> impl Iterator<Item = i64> { 0..n }").unwrap();
+    let f = Function::from_item(&item, &[]).unwrap();
+    assert!(f.returns_set);
+    assert!(f.to_sql().starts_with("create function series(n bigint) returns setof bigint as"));
+    let item : ItemFn = syn::parse_str("fn split(t : Text) -> (Text, Text) { unimplemented!() }").unwrap();
+    let opts : syn::AttributeArgs = vec![syn::parse_str("columns(head, tail)").unwrap()];
+    let f = Function::from_item(&item, &opts).unwrap();
+    assert!(f.to_sql().starts_with("create function split(t text, out head text, out tail text) returns record as"));
+    let item : ItemFn = syn::parse_str("fn pairs(n : i32) -> impl Iterator<Item = Pair> { unimplemented!() }").unwrap();
+    let mut f = Function::from_item(&item, &[]).unwrap();
+    assert_eq!(f.ret, Returns::Struct(String::from("Pair")));
+    let mut rows = HashMap::new();
+    let mut composites = HashSet::new();
+    assert!(f.clone().resolve_rows(&rows, &composites).is_err());
+    composites.insert(String::from("Pair"));
+    let mut g = f.clone();
+    g.resolve_rows(&rows, &composites).unwrap();
+    assert!(g.to_sql().starts_with("create function pairs(n integer) returns setof pair as"));
+    let col = |name : &str| Argument { name : String::from(name), ty : String::from("integer") };
+    rows.insert(String::from("Pair"), vec![col("a"), col("b")]);
+    f.resolve_rows(&rows, &HashSet::new()).unwrap();
+    assert!(f.to_sql().starts_with("create function pairs(n integer) returns table (a integer, b integer) as"));
+    let item : ItemFn = syn::parse_str("fn first<T>(a : T) -> T { a }").unwrap();
+    assert!(Function::from_item(&item, &[]).is_err());
+}
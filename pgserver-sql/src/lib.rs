@@ -0,0 +1,28 @@
+//! Representation of the SQL objects exported by a pgserver extension. This crate is shared by the
+//! procedural macros (which validate the annotated items and generate their entry points) and by
+//! the build utilities (which write the extension script), so both always agree on the SQL
+//! declaration of a given Rust item.
+
+/// Mapping between Rust types and the SQL types they are declared as
+pub mod types;
+
+/// CREATE FUNCTION declarations derived from Rust function signatures
+pub mod function;
+
+/// Rows and composite types declared by Rust structs
+pub mod composite;
+
+/// CREATE AGGREGATE declarations derived from implementations of the Aggregate trait
+pub mod aggregate;
+
+/// CREATE EVENT TRIGGER declarations derived from event trigger functions
+pub mod event_trigger;
+
+/// Collection of the exported items of a crate into an extension script
+pub mod extension;
+
+pub use function::{Function, Argument, Returns, Options, Volatility, CastContext};
+pub use composite::Composite;
+pub use aggregate::Aggregate;
+pub use event_trigger::EventTrigger;
+pub use extension::SqlItem;
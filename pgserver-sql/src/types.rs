@@ -0,0 +1,242 @@
+use syn::{Type, Error, PathArguments, GenericArgument, TypeParamBound};
+use quote::ToTokens;
+use std::collections::HashSet;
+
+/// If the type is a generic wrapper named wrapper (e.g. Option<T>), returns its type argument.
+fn generic_inner<'a>(ty : &'a Type, wrapper : &str) -> Option<&'a Type> {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => {
+            let last = path.path.segments.last()?;
+            match &last.arguments {
+                PathArguments::AngleBracketed(args) if last.ident == wrapper && args.args.len() == 1 => {
+                    match &args.args[0] {
+                        GenericArgument::Type(inner) => Some(inner),
+                        _ => None
+                    }
+                },
+                _ => None
+            }
+        },
+        Type::Paren(paren) => generic_inner(&paren.elem, wrapper),
+        Type::Group(group) => generic_inner(&group.elem, wrapper),
+        _ => None
+    }
+}
+
+/// If the type is Option<T>, returns T.
+pub fn option_inner(ty : &Type) -> Option<&Type> {
+    generic_inner(ty, "Option")
+}
+
+/// If the type is impl Iterator<Item = T> (possibly with further bounds, such as lifetimes),
+/// returns T.
+pub fn iterator_item(ty : &Type) -> Option<&Type> {
+    match ty {
+        Type::ImplTrait(impl_trait) => {
+            impl_trait.bounds.iter().find_map(|bound| {
+                let trait_bound = match bound {
+                    TypeParamBound::Trait(trait_bound) => trait_bound,
+                    TypeParamBound::Lifetime(_) => return None
+                };
+                let last = trait_bound.path.segments.last()?;
+                match &last.arguments {
+                    PathArguments::AngleBracketed(args) if last.ident == "Iterator" => {
+                        args.args.iter().find_map(|arg| match arg {
+                            GenericArgument::Binding(binding) if binding.ident == "Item" => Some(&binding.ty),
+                            _ => None
+                        })
+                    },
+                    _ => None
+                }
+            })
+        },
+        Type::Paren(paren) => iterator_item(&paren.elem),
+        Type::Group(group) => iterator_item(&group.elem),
+        _ => None
+    }
+}
+
+/// Rust types with a built-in SQL equivalent, and the name they are declared with.
+const BUILTIN_TYPES : [(&str, &str); 12] = [
+    ("bool", "boolean"),
+    ("i16", "smallint"),
+    ("i32", "integer"),
+    ("i64", "bigint"),
+    ("f32", "real"),
+    ("f64", "double precision"),
+    ("Text", "text"),
+    ("VarChar", "varchar"),
+    ("BpChar", "bpchar"),
+    ("Bytea", "bytea"),
+    ("Oid", "oid"),
+    ("Row", "record")
+];
+
+/// Names of the types at pg_catalog (as of PostgreSQL 15, excluding arrays and the row types of
+/// the system catalogs), followed by the aliases accepted by the SQL grammar. Type names are
+/// looked up at pg_catalog before the schema of the extension, so a composite type named after
+/// any of them could not be referred to by its name.
+const RESERVED_TYPES : [&str; 118] = [
+    "aclitem", "any", "anyarray", "anycompatible", "anycompatiblearray", "anycompatiblemultirange",
+    "anycompatiblenonarray", "anycompatiblerange", "anyelement", "anyenum", "anymultirange",
+    "anynonarray", "anyrange", "bit", "bool", "box", "bpchar", "bytea", "char", "cid", "cidr",
+    "circle", "cstring", "date", "datemultirange", "daterange", "event_trigger", "fdw_handler",
+    "float4", "float8", "gtsvector", "index_am_handler", "inet", "int2", "int2vector", "int4",
+    "int4multirange", "int4range", "int8", "int8multirange", "int8range", "internal", "interval",
+    "json", "jsonb", "jsonpath", "language_handler", "line", "lseg", "macaddr", "macaddr8", "money",
+    "name", "numeric", "nummultirange", "numrange", "oid", "oidvector", "path",
+    "pg_brin_bloom_summary", "pg_brin_minmax_multi_summary", "pg_ddl_command", "pg_dependencies",
+    "pg_lsn", "pg_mcv_list", "pg_ndistinct", "pg_node_tree", "pg_snapshot", "point", "polygon",
+    "record", "refcursor", "regclass", "regcollation", "regconfig", "regdictionary", "regnamespace",
+    "regoper", "regoperator", "regproc", "regprocedure", "regrole", "regtype", "table_am_handler",
+    "text", "tid", "time", "timestamp", "timestamptz", "timetz", "trigger", "tsm_handler",
+    "tsmultirange", "tsquery", "tsrange", "tstzmultirange", "tstzrange", "tsvector",
+    "txid_snapshot", "unknown", "uuid", "varbit", "varchar", "void", "xid", "xid8", "xml",
+    "bigint", "boolean", "character", "dec", "decimal", "float", "int", "integer", "nchar", "real",
+    "smallint"
+];
+
+/// SQL name of the built-in types with a Rust representation.
+fn builtin_type(ident : &str) -> Option<&'static str> {
+    BUILTIN_TYPES.iter().find(|(rust, _)| *rust == ident ).map(|(_, sql)| *sql )
+}
+
+/// Whether a composite type cannot be given this name, since it is taken by a built-in type.
+pub fn is_reserved(name : &str) -> bool {
+    RESERVED_TYPES.contains(&name)
+}
+
+/// If the type is a path to a user-defined struct (a non-generic type that is not a built-in),
+/// returns the struct name. Such structs are taken as rows or composite types; the extension
+/// script only accepts the ones deriving PgRow or PgComposite (see check_structs).
+pub fn struct_name(ty : &Type) -> Option<String> {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => {
+            let last = path.path.segments.last()?;
+            let ident = last.ident.to_string();
+            let is_struct = last.arguments.is_empty() &&
+                builtin_type(&ident).is_none() &&
+                ident.starts_with(|c : char| c.is_ascii_uppercase() ) &&
+                ident != "String" && ident != "Self";
+            if is_struct { Some(ident) } else { None }
+        },
+        Type::Paren(paren) => struct_name(&paren.elem),
+        Type::Group(group) => struct_name(&group.elem),
+        _ => None
+    }
+}
+
+/// Checks that the structs the type refers to (by itself, or through Option, Array, impl Iterator
+/// and tuples) are among structs. Any other type named like a struct (such as a type alias, or a
+/// struct that does not derive PgComposite) has no SQL type, so the error points at it.
+pub fn check_structs(ty : &Type, structs : &HashSet<String>) -> Result<(), Error> {
+    if let Some(inner) = option_inner(ty).or_else(|| generic_inner(ty, "Array") ).or_else(|| iterator_item(ty) ) {
+        return check_structs(inner, structs);
+    }
+    match ty {
+        Type::Tuple(tuple) => tuple.elems.iter().try_for_each(|elem| check_structs(elem, structs) ),
+        Type::Paren(paren) => check_structs(&paren.elem, structs),
+        Type::Group(group) => check_structs(&group.elem, structs),
+        _ => match struct_name(ty) {
+            Some(name) if !structs.contains(&name) => Err(Error::new_spanned(
+                ty,
+                format!("Type {} has no PostgreSQL equivalent (structs must derive PgComposite)", name)
+            )),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Name of the composite type declared for a struct deriving PgComposite: The struct name in
+/// snake case (GeoPoint becomes geo_point).
+pub fn composite_name(struct_name : &str) -> String {
+    snake_case(struct_name)
+}
+
+/// Converts a Rust type name (in camel case) to snake case.
+pub fn snake_case(type_name : &str) -> String {
+    let mut name = String::new();
+    for (i, c) in type_name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                name.push('_');
+            }
+            name.extend(c.to_lowercase());
+        } else {
+            name.push(c);
+        }
+    }
+    name
+}
+
+/// Returns the SQL type name a Rust type is declared as in a function signature. Types are
+/// resolved by their last path segment, so Text, pgserver::Text and crate::Text all map to text.
+/// The unit type maps to void, Row maps to record (so any composite value is accepted), and
+/// Option<T> maps to the same type as T (the option only determines whether NULL values are
+/// accepted). Array<T> maps to the array of the SQL type of T, and other structs map to their
+/// composite type (see composite_name), which must be declared by the extension.
+pub fn sql_type(ty : &Type) -> Result<String, Error> {
+    if let Some(inner) = option_inner(ty) {
+        return sql_type(inner);
+    }
+    if let Some(elem) = generic_inner(ty, "Array") {
+        if option_inner(elem).is_some() {
+            return Err(Error::new_spanned(elem, "Array elements are always nullable: use Array<T> instead of Array<Option<T>>"));
+        }
+        return Ok(format!("{}[]", sql_type(elem)?));
+    }
+    if let Some(name) = struct_name(ty) {
+        return Ok(composite_name(&name));
+    }
+    match ty {
+        Type::Tuple(tuple) if tuple.elems.is_empty() => Ok(String::from("void")),
+        Type::Paren(paren) => sql_type(&paren.elem),
+        Type::Group(group) => sql_type(&group.elem),
+        Type::Path(path) if path.qself.is_none() => {
+            let last = path.path.segments.last()
+                .ok_or_else(|| unsupported(ty) )?;
+            if !last.arguments.is_empty() {
+                return Err(unsupported(ty));
+            }
+            builtin_type(&last.ident.to_string())
+                .map(String::from)
+                .ok_or_else(|| unsupported(ty) )
+        },
+        _ => Err(unsupported(ty))
+    }
+}
+
+fn unsupported(ty : &Type) -> Error {
+    Error::new_spanned(
+        ty,
+        format!("Type {} has no PostgreSQL equivalent", ty.to_token_stream())
+    )
+}
+
+#[test]
+fn map_types() {
+    let parse = |s : &str| sql_type(&syn::parse_str::<Type>(s).unwrap());
+    assert_eq!(parse("i32").unwrap(), "integer");
+    assert_eq!(parse("f64").unwrap(), "double precision");
+    assert_eq!(parse("pgserver::Text").unwrap(), "text");
+    assert_eq!(parse("()").unwrap(), "void");
+    assert_eq!(parse("Row").unwrap(), "record");
+    assert_eq!(parse("Option<Bytea>").unwrap(), "bytea");
+    assert_eq!(parse("Array<f64>").unwrap(), "double precision[]");
+    assert!(parse("Array<Option<f64>>").is_err());
+    assert!(parse("Vec<u8>").is_err());
+    assert!(parse("&str").is_err());
+    assert!(parse("u32").is_err());
+    assert!(parse("String").is_err());
+    assert_eq!(parse("Option<GeoPoint>").unwrap(), "geo_point");
+    assert_eq!(parse("Array<GeoPoint>").unwrap(), "geo_point[]");
+    let check = |s : &str| check_structs(&syn::parse_str::<Type>(s).unwrap(), &[String::from("GeoPoint")].iter().cloned().collect());
+    assert!(check("impl Iterator<Item = (Option<GeoPoint>, Array<GeoPoint>, i32)>").is_ok());
+    assert!(check("Option<Score>").unwrap_err().to_string().starts_with("Type Score has no PostgreSQL equivalent"));
+    assert!(is_reserved(&composite_name("Point")) && is_reserved(&composite_name("Int")));
+    assert!(!is_reserved(&composite_name("GeoPoint")));
+    let item = |s : &str| iterator_item(&syn::parse_str::<Type>(s).unwrap()).map(|t| sql_type(t).unwrap() );
+    assert_eq!(item("impl Iterator<Item = i64>"), Some(String::from("bigint")));
+    assert_eq!(item("impl Iterator<Item = Option<Text>> + 'static"), Some(String::from("text")));
+    assert_eq!(item("Vec<i64>"), None);
+}
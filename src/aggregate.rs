@@ -0,0 +1,597 @@
+use std::os::raw::{c_int, c_void};
+use std::{mem, ptr};
+use std::cmp::Ordering;
+use std::ops::Deref;
+use super::datum::{Datum, Oid, IntoDatum};
+use super::fmgr::{self, FunctionCallInfo};
+use super::ffi::{self, MemoryContext, MemoryContextCallback};
+use super::{log, error, pg_config, Bytea, Text};
+
+/// User-defined aggregate. The aggregate keeps a Rust value of type State for each group, which
+/// accum updates for each input row and finalize converts into the result. Annotating the impl
+/// block with #[pg_aggregate] generates the support functions and the CREATE AGGREGATE statement
+/// (the aggregate is named after the type in snake case, unless name = "..." is given):
+///
+/// ```rust,ignore
+/// pub struct Mean;
+///
+/// #[pg_aggregate(immutable, parallel_safe)]
+/// impl Aggregate for Mean {
+///     type State = (f64, i64);
+///     type Args = f64;
+///     type Output = Option<f64>;
+///
+///     fn init() -> (f64, i64) {
+///         (0.0, 0)
+///     }
+///
+///     fn accum(state : &mut (f64, i64), value : f64) {
+///         state.0 += value;
+///         state.1 += 1;
+///     }
+///
+///     fn finalize(state : &(f64, i64)) -> Option<f64> {
+///         if state.1 == 0 { None } else { Some(state.0 / state.1 as f64) }
+///     }
+///
+///     fn combine(state : &mut (f64, i64), other : &(f64, i64)) {
+///         state.0 += other.0;
+///         state.1 += other.1;
+///     }
+///
+///     fn serialize(state : &(f64, i64)) -> Bytea {
+///         let mut bytes = state.0.to_le_bytes().to_vec();
+///         bytes.extend(&state.1.to_le_bytes());
+///         Bytea::from(&bytes[..])
+///     }
+///
+///     fn deserialize(bytes : Bytea) -> (f64, i64) {
+///         let (sum, count) = bytes.as_ref().split_at(8);
+///         (f64::from_le_bytes(sum.try_into().unwrap()), i64::from_le_bytes(count.try_into().unwrap()))
+///     }
+/// }
+/// ```
+///
+/// Args is either a single type or a tuple (for aggregates over several columns, or over * if
+/// the tuple is empty). Rows where a non-Option argument is NULL are skipped, as they are for
+/// strict transition functions. The state is created by init when the first row of a group is
+/// seen, lives in the aggregate memory context and is dropped when the server resets that
+/// context. The parallel mode of the aggregation requires combine, serialize and deserialize.
+/// Defining retract enables the moving-aggregate mode, which lets window frames that slide
+/// (e.g. ROWS BETWEEN 2 PRECEDING AND CURRENT ROW) remove the rows leaving the frame from the
+/// state instead of aggregating the whole frame again for each row.
+pub trait Aggregate {
+
+    type State;
+
+    type Args;
+
+    type Output : IntoDatum;
+
+    /// Initial state of each group (the state finalize receives for empty groups).
+    fn init() -> Self::State;
+
+    /// Adds an input row to the state.
+    fn accum(state : &mut Self::State, args : Self::Args);
+
+    /// Removes an input row previously added by accum from the state. Returns false if the row
+    /// cannot be removed (e.g. the state is a maximum that just left the frame), in which case
+    /// the server aggregates the frame again from a new state.
+    fn retract(_state : &mut Self::State, _args : Self::Args) -> bool {
+        log::Error::raise("Aggregate does not implement retract")
+    }
+
+    /// Computes the result from the state. The state might be finalized more than once (e.g.
+    /// when the aggregate is used as a window function), so it is not modified here.
+    fn finalize(state : &Self::State) -> Self::Output;
+
+    /// Merges the state of another worker into this state.
+    fn combine(_state : &mut Self::State, _other : &Self::State) {
+        log::Error::raise("Aggregate does not implement combine")
+    }
+
+    /// Converts the state into bytes, to be sent between parallel workers.
+    fn serialize(_state : &Self::State) -> Bytea {
+        log::Error::raise("Aggregate does not implement serialize")
+    }
+
+    /// Restores a state converted by serialize.
+    fn deserialize(_bytes : Bytea) -> Self::State {
+        log::Error::raise("Aggregate does not implement deserialize")
+    }
+
+}
+
+/// Memory holding the state of a group, allocated in the aggregate memory context together
+/// with the callback that drops the state when the context is reset.
+#[repr(C)]
+struct StateCell<S> {
+    state : S,
+    callback : MemoryContextCallback
+}
+
+unsafe extern "C" fn drop_state<S>(arg : *mut c_void) {
+    error::boundary(|| ptr::drop_in_place(&mut (*(arg as *mut StateCell<S>)).state) );
+}
+
+/// Memory context that lives as long as the aggregate states (AggCheckCallContext). Raises an
+/// error if the function is not called as part of an aggregate.
+pub unsafe fn agg_context(fcinfo : FunctionCallInfo) -> MemoryContext {
+    let mut context : MemoryContext = ptr::null_mut();
+    if ffi::AggCheckCallContext(fcinfo, &mut context) == 0 {
+        log::Error::raise("Aggregate support function called in non-aggregate context");
+    }
+    context
+}
+
+// Moves the state into the aggregate memory context.
+unsafe fn store_state<S>(fcinfo : FunctionCallInfo, state : S) -> *mut StateCell<S> {
+    if mem::align_of::<StateCell<S>>() > pg_config::MAXIMUM_ALIGNOF {
+        log::Error::raise("Aggregate state alignment exceeds the alignment of palloc'd memory");
+    }
+    let context = agg_context(fcinfo);
+    let cell = ffi::MemoryContextAlloc(context, mem::size_of::<StateCell<S>>()) as *mut StateCell<S>;
+    cell.write(StateCell {
+        state,
+        callback : MemoryContextCallback {
+            func : Some(drop_state::<S>),
+            arg : cell as *mut c_void,
+            next : ptr::null_mut()
+        }
+    });
+    ffi::MemoryContextRegisterResetCallback(context, &mut (*cell).callback);
+    cell
+}
+
+// State passed as the n-th argument, if not NULL.
+unsafe fn state_arg<S>(fcinfo : FunctionCallInfo, n : usize) -> Option<*mut StateCell<S>> {
+    if fmgr::arg_is_null(fcinfo, n) {
+        None
+    } else {
+        Some(fmgr::arg_datum(fcinfo, n).as_ptr())
+    }
+}
+
+/// Transition function: Adds the arguments returned by read_args (which returns None for rows
+/// that must be skipped) to the state, creating the state at the first row of the group.
+pub unsafe fn accum<A, F>(fcinfo : FunctionCallInfo, read_args : F) -> Datum
+where
+    A : Aggregate,
+    F : FnOnce() -> Option<A::Args>
+{
+    let cell = match state_arg::<A::State>(fcinfo, 0) {
+        Some(cell) => cell,
+        None => store_state(fcinfo, A::init())
+    };
+    if let Some(args) = read_args() {
+        A::accum(&mut (*cell).state, args);
+    }
+    Datum::from_ptr(cell)
+}
+
+/// Inverse transition function: Removes the arguments returned by read_args (which returns None
+/// for the rows accum skipped) from the state. Returns NULL if the aggregate could not remove
+/// them, which makes the server restart the aggregation of the frame.
+pub unsafe fn retract<A, F>(fcinfo : FunctionCallInfo, read_args : F) -> Datum
+where
+    A : Aggregate,
+    F : FnOnce() -> Option<A::Args>
+{
+    let cell = match state_arg::<A::State>(fcinfo, 0) {
+        Some(cell) => cell,
+        None => log::Error::raise("Inverse transition function called without a state")
+    };
+    let removed = match read_args() {
+        Some(args) => A::retract(&mut (*cell).state, args),
+        None => true
+    };
+    if removed {
+        Datum::from_ptr(cell)
+    } else {
+        (*fcinfo).isnull = true;
+        Datum::default()
+    }
+}
+
+/// Final function: Computes the result from the state (or from a new state, for empty groups).
+pub unsafe fn finalize<A : Aggregate>(fcinfo : FunctionCallInfo) -> Datum {
+    agg_context(fcinfo);
+    match state_arg::<A::State>(fcinfo, 0) {
+        Some(cell) => fmgr::ret(fcinfo, A::finalize(&(*cell).state)),
+        None => fmgr::ret(fcinfo, A::finalize(&A::init()))
+    }
+}
+
+/// Combine function: Merges the second state into the first one. Either might be NULL, if the
+/// worker that produced it saw no rows.
+pub unsafe fn combine<A : Aggregate>(fcinfo : FunctionCallInfo) -> Datum {
+    let state = state_arg::<A::State>(fcinfo, 0);
+    match (state, state_arg::<A::State>(fcinfo, 1)) {
+        (Some(cell), Some(other)) => {
+            A::combine(&mut (*cell).state, &(*other).state);
+            Datum::from_ptr(cell)
+        },
+        (None, Some(other)) => {
+            let cell = store_state(fcinfo, A::init());
+            A::combine(&mut (*cell).state, &(*other).state);
+            Datum::from_ptr(cell)
+        },
+        (Some(cell), None) => Datum::from_ptr(cell),
+        (None, None) => {
+            (*fcinfo).isnull = true;
+            Datum::default()
+        }
+    }
+}
+
+/// Serial function: Converts the state into a bytea.
+pub unsafe fn serialize<A : Aggregate>(fcinfo : FunctionCallInfo) -> Datum {
+    agg_context(fcinfo);
+    let cell : *mut StateCell<A::State> = fmgr::arg_datum(fcinfo, 0).as_ptr();
+    fmgr::ret(fcinfo, A::serialize(&(*cell).state))
+}
+
+/// Deserial function: Restores a state from a bytea into the aggregate memory context.
+pub unsafe fn deserialize<A : Aggregate>(fcinfo : FunctionCallInfo) -> Datum {
+    let bytes = fmgr::arg::<Bytea>(fcinfo, 0);
+    Datum::from_ptr(store_state(fcinfo, A::deserialize(bytes)))
+}
+
+
+/// User-defined ordered-set aggregate, called as name(direct args) WITHIN GROUP (ORDER BY args).
+/// The aggregated rows are collected for each group, and sorted as the ORDER BY clause says
+/// before finalize receives them together with the direct arguments. Annotating the impl block
+/// with #[pg_aggregate] declares the aggregate, as it does for Aggregate:
+///
+/// ```rust,ignore
+/// pub struct Percentile;
+///
+/// #[pg_aggregate(immutable)]
+/// impl OrderedSetAggregate for Percentile {
+///     type DirectArgs = f64;
+///     type Args = f64;
+///     type Output = Option<f64>;
+///
+///     fn finalize(fraction : f64, sorted : &Sorted<f64>) -> Option<f64> {
+///         let pos = (fraction * (sorted.len() as f64 - 1.0)).round();
+///         sorted.get(pos as usize).copied()
+///     }
+/// }
+/// ```
+///
+/// As for Aggregate, rows where a non-Option aggregated argument is NULL are skipped. The result
+/// is NULL if a non-Option direct argument is NULL. With #[pg_aggregate(hypothetical)], the
+/// aggregate is declared as a hypothetical-set aggregate (such as rank(...) WITHIN GROUP), whose
+/// last direct arguments are a row of the same types as the aggregated arguments (the server
+/// then resolves the types of both together).
+pub trait OrderedSetAggregate {
+
+    type DirectArgs;
+
+    type Args : SortColumns;
+
+    type Output : IntoDatum;
+
+    /// Computes the result from the direct arguments and the sorted rows of the group (which
+    /// might be empty).
+    fn finalize(direct : Self::DirectArgs, sorted : &Sorted<Self::Args>) -> Self::Output;
+
+}
+
+/// Sort order of an aggregated argument, as given by WITHIN GROUP (ORDER BY ...). The default
+/// is the ascending order, with NULLs last.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SortKey {
+    pub descending : bool,
+    pub nulls_first : bool
+}
+
+/// Aggregated argument of an ordered-set aggregate. Values are compared in ascending order
+/// (which the sort key might reverse): Numbers by value (with NaN greater than any other value,
+/// as the server orders them), text and bytea by their bytes (as with COLLATE "C").
+pub trait SortColumn : Sized {
+
+    /// Copies the value into the current memory context, so it outlives the input row.
+    fn copy_value(&self) -> Self;
+
+    fn compare_value(&self, other : &Self) -> Ordering;
+
+    fn is_null(&self) -> bool {
+        false
+    }
+
+}
+
+macro_rules! sort_column_ord {
+    ($($ty:ty),*) => {
+        $(
+            impl SortColumn for $ty {
+                fn copy_value(&self) -> Self {
+                    *self
+                }
+
+                fn compare_value(&self, other : &Self) -> Ordering {
+                    self.cmp(other)
+                }
+            }
+        )*
+    };
+}
+
+sort_column_ord!(bool, i16, i32, i64);
+
+macro_rules! sort_column_float {
+    ($($ty:ty),*) => {
+        $(
+            impl SortColumn for $ty {
+                fn copy_value(&self) -> Self {
+                    *self
+                }
+
+                fn compare_value(&self, other : &Self) -> Ordering {
+                    self.partial_cmp(other).unwrap_or_else(|| self.is_nan().cmp(&other.is_nan()) )
+                }
+            }
+        )*
+    };
+}
+
+sort_column_float!(f32, f64);
+
+impl SortColumn for Text {
+
+    fn copy_value(&self) -> Self {
+        Text::from(self.as_ref())
+    }
+
+    fn compare_value(&self, other : &Self) -> Ordering {
+        self.as_ref().cmp(other.as_ref())
+    }
+
+}
+
+impl SortColumn for Bytea {
+
+    fn copy_value(&self) -> Self {
+        Bytea::from(self.as_ref())
+    }
+
+    fn compare_value(&self, other : &Self) -> Ordering {
+        self.as_ref().cmp(other.as_ref())
+    }
+
+}
+
+impl<T : SortColumn> SortColumn for Option<T> {
+
+    fn copy_value(&self) -> Self {
+        self.as_ref().map(|v| v.copy_value() )
+    }
+
+    fn compare_value(&self, other : &Self) -> Ordering {
+        match (self, other) {
+            (Some(a), Some(b)) => a.compare_value(b),
+            (a, b) => b.is_none().cmp(&a.is_none())
+        }
+    }
+
+    fn is_null(&self) -> bool {
+        self.is_none()
+    }
+
+}
+
+fn compare_column<T : SortColumn>(a : &T, b : &T, key : SortKey) -> Ordering {
+    match (a.is_null(), b.is_null()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => if key.nulls_first { Ordering::Less } else { Ordering::Greater },
+        (false, true) => if key.nulls_first { Ordering::Greater } else { Ordering::Less },
+        (false, false) if key.descending => a.compare_value(b).reverse(),
+        (false, false) => a.compare_value(b)
+    }
+}
+
+/// Aggregated arguments of an ordered-set aggregate: A single SortColumn, or a tuple of them
+/// (compared column by column, each with its own sort key).
+pub trait SortColumns : Sized {
+
+    /// Copies the values into the current memory context, so they outlive the input row.
+    fn copy(&self) -> Self;
+
+    fn compare(&self, other : &Self, keys : &[SortKey]) -> Ordering;
+
+}
+
+impl<T : SortColumn> SortColumns for T {
+
+    fn copy(&self) -> Self {
+        self.copy_value()
+    }
+
+    fn compare(&self, other : &Self, keys : &[SortKey]) -> Ordering {
+        compare_column(self, other, keys.first().copied().unwrap_or_default())
+    }
+
+}
+
+macro_rules! sort_columns_tuple {
+    ($($ty:ident $ix:tt),*) => {
+        impl<$($ty : SortColumn),*> SortColumns for ($($ty,)*) {
+
+            fn copy(&self) -> Self {
+                ($(self.$ix.copy_value(),)*)
+            }
+
+            fn compare(&self, other : &Self, keys : &[SortKey]) -> Ordering {
+                Ordering::Equal
+                    $(.then_with(|| compare_column(&self.$ix, &other.$ix, keys.get($ix).copied().unwrap_or_default()) ))*
+            }
+
+        }
+    };
+}
+
+sort_columns_tuple!(A 0, B 1);
+sort_columns_tuple!(A 0, B 1, C 2);
+sort_columns_tuple!(A 0, B 1, C 2, D 3);
+sort_columns_tuple!(A 0, B 1, C 2, D 3, E 4);
+sort_columns_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+
+/// Rows of a group of an ordered-set aggregate, in the order given by WITHIN GROUP (ORDER BY ...).
+/// Dereferences to the slice of rows.
+pub struct Sorted<'a, T> {
+    rows : &'a [T],
+    keys : Vec<SortKey>
+}
+
+impl<T : SortColumns> Sorted<'_, T> {
+
+    /// Sort keys of the aggregated arguments, in the order they were given.
+    pub fn keys(&self) -> &[SortKey] {
+        &self.keys
+    }
+
+    /// Compares two rows as the sort keys say, which allows placing a row that is not part
+    /// of the group (such as the hypothetical row of a hypothetical-set aggregate):
+    ///
+    /// ```rust,ignore
+    /// let rank = sorted.partition_point(|row| sorted.compare(row, &hypothetical) == Ordering::Less) + 1;
+    /// ```
+    pub fn compare(&self, a : &T, b : &T) -> Ordering {
+        a.compare(b, &self.keys)
+    }
+
+}
+
+impl<T> Deref for Sorted<'_, T> {
+
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.rows
+    }
+
+}
+
+/// ABI-compatible with the leading fields of Aggref (primnodes.h), the planner node of an
+/// aggregate call.
+#[repr(C)]
+pub struct Aggref {
+    pub xpr_type : c_int,
+    pub aggfnoid : Oid,
+    pub aggtype : Oid,
+    pub aggcollid : Oid,
+    pub inputcollid : Oid,
+    pub aggtranstype : Oid,
+    pub aggargtypes : *mut List,
+    pub aggdirectargs : *mut List,
+    pub args : *mut List,
+    pub aggorder : *mut List
+}
+
+/// ABI-compatible with the leading fields of List (pg_list.h), for lists of pointers.
+#[repr(C)]
+pub struct List {
+    pub list_type : c_int,
+    pub length : c_int,
+    pub max_length : c_int,
+    pub elements : *mut *mut c_void
+}
+
+/// ABI-compatible with SortGroupClause (parsenodes.h), an item of ORDER BY.
+#[repr(C)]
+pub struct SortGroupClause {
+    pub clause_type : c_int,
+    pub tle_sort_group_ref : u32,
+    pub eqop : Oid,
+    pub sortop : Oid,
+    #[cfg(pg_ge_18)]
+    pub reverse_sort : bool,
+    pub nulls_first : bool,
+    pub hashable : bool
+}
+
+// Aggref, List and SortGroupClause are not part of the interface kept stable for extensions, and
+// the offsets of the fields read by sort_keys were checked against the headers of versions 13 to 18
+// only (SortGroupClause gained reverse_sort at 18).
+const _ : () = assert!(
+    pg_config::PG_VERSION_NUM < 190000,
+    "The offsets of Aggref.aggorder, List.length, List.elements, SortGroupClause.sortop and \
+    SortGroupClause.nulls_first must be checked for this server version"
+);
+
+// Sort keys of the WITHIN GROUP (ORDER BY ...) clause of the aggregate being computed. Whether
+// a key is descending is given by its sort operator (> instead of <).
+unsafe fn sort_keys(fcinfo : FunctionCallInfo) -> Vec<SortKey> {
+    let aggref = ffi::AggGetAggref(fcinfo);
+    if aggref.is_null() || (*aggref).aggorder.is_null() {
+        return Vec::new();
+    }
+    let order = (*aggref).aggorder;
+    (0..(*order).length as usize).map(|i| {
+        let clause = *(*order).elements.add(i) as *const SortGroupClause;
+        let mut descending = false;
+        ffi::get_equality_op_for_ordering_op((*clause).sortop, &mut descending);
+        SortKey { descending, nulls_first : (*clause).nulls_first }
+    }).collect()
+}
+
+/// Transition function of an ordered-set aggregate: Adds the arguments returned by read_args
+/// (which returns None for rows that must be skipped) to the rows of the group, copying them
+/// into the aggregate memory context.
+pub unsafe fn ordered_accum<A, F>(fcinfo : FunctionCallInfo, read_args : F) -> Datum
+where
+    A : OrderedSetAggregate,
+    F : FnOnce() -> Option<A::Args>
+{
+    let cell = match state_arg::<Vec<A::Args>>(fcinfo, 0) {
+        Some(cell) => cell,
+        None => store_state(fcinfo, Vec::new())
+    };
+    if let Some(args) = read_args() {
+        let old_ctx = ffi::CurrentMemoryContext;
+        ffi::CurrentMemoryContext = agg_context(fcinfo);
+        (*cell).state.push(args.copy());
+        ffi::CurrentMemoryContext = old_ctx;
+    }
+    Datum::from_ptr(cell)
+}
+
+/// Final function of an ordered-set aggregate: Sorts the rows of the group and computes the
+/// result from them and the direct arguments returned by read_direct (which returns None if
+/// the result is NULL).
+pub unsafe fn ordered_finalize<A, F>(fcinfo : FunctionCallInfo, read_direct : F) -> Datum
+where
+    A : OrderedSetAggregate,
+    F : FnOnce() -> Option<A::DirectArgs>
+{
+    agg_context(fcinfo);
+    let direct = match read_direct() {
+        Some(direct) => direct,
+        None => {
+            (*fcinfo).isnull = true;
+            return Datum::default();
+        }
+    };
+    let keys = sort_keys(fcinfo);
+    let mut empty = Vec::new();
+    let rows = match state_arg::<Vec<A::Args>>(fcinfo, 0) {
+        Some(cell) => &mut (*cell).state,
+        None => &mut empty
+    };
+    rows.sort_by(|a, b| a.compare(b, &keys) );
+    fmgr::ret(fcinfo, A::finalize(direct, &Sorted { rows, keys }))
+}
+
+#[test]
+fn sort_rows() {
+    let mut rows = [(Some(2), 1.0), (None, 3.0), (Some(2), f64::NAN), (Some(1), 2.0)];
+    let keys = [SortKey { descending : true, nulls_first : false }, SortKey::default()];
+    rows.sort_by(|a, b| a.compare(b, &keys) );
+    assert_eq!(rows[0], (Some(2), 1.0));
+    assert!(rows[1].1.is_nan());
+    assert_eq!(&rows[2..], &[(Some(1), 2.0), (None, 3.0)]);
+    let mut values = [None, Some(3), Some(1)];
+    values.sort_by(|a, b| a.compare(b, &[SortKey { descending : false, nulls_first : true }]) );
+    assert_eq!(values, [None, Some(1), Some(3)]);
+}
@@ -0,0 +1,448 @@
+use std::marker::PhantomData;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::{mem, slice};
+use super::datum::{Datum, Oid, FromDatum, IntoDatum};
+use super::vla::{self, varlena};
+use super::{ffi, log, pg_config};
+
+/// ABI-compatible with ArrayType (array.h). The header is followed by the dimensions and lower
+/// bounds (ndim integers each), by the null bitmap (only if dataoffset is nonzero) and by the
+/// element data.
+#[repr(C)]
+pub(crate) struct ArrayType {
+    vl_len_ : i32,
+    ndim : i32,
+    dataoffset : i32,
+    elemtype : Oid
+}
+
+/// Storage properties of an element type (pg_type.typlen, typbyval and typalign).
+#[derive(Clone, Copy, Debug)]
+struct ElemLayout {
+    len : i16,
+    byval : bool,
+    align : c_char
+}
+
+impl ElemLayout {
+
+    fn of(elem_type : Oid) -> Self {
+        let mut layout = ElemLayout { len : 0, byval : false, align : 0 };
+        unsafe { ffi::get_typlenbyvalalign(elem_type, &mut layout.len, &mut layout.byval, &mut layout.align) };
+        layout
+    }
+
+    // att_align_nominal
+    fn align(&self, offset : usize) -> usize {
+        let align = match self.align as u8 {
+            b'c' => 1,
+            b's' => 2,
+            b'i' => 4,
+            _ => 8
+        };
+        (offset + align - 1) & !(align - 1)
+    }
+
+}
+
+/// Maximum number of array dimensions (MAXDIM, at array.h).
+pub const MAXDIM : usize = 6;
+
+fn maxalign(len : usize) -> usize {
+    (len + pg_config::MAXIMUM_ALIGNOF - 1) & !(pg_config::MAXIMUM_ALIGNOF - 1)
+}
+
+/// Types whose array elements are stored contiguously with the same layout they have in Rust,
+/// so a null-free array of them can be viewed as a slice without copying.
+pub trait FixedWidth : FromDatum + Copy { }
+
+impl FixedWidth for bool { }
+impl FixedWidth for i16 { }
+impl FixedWidth for i32 { }
+impl FixedWidth for i64 { }
+impl FixedWidth for f32 { }
+impl FixedWidth for f64 { }
+impl FixedWidth for Oid { }
+
+/// A PostgreSQL array (int4[], float8[][], text[], etc.) with elements of type T. Receiving an
+/// Array<T> argument checks that the array element type is compatible with T, and the elements
+/// can be read either through the iterator (which yields None for NULL elements) or, for null-free
+/// arrays of fixed-width types, as a slice. Multi-dimensional arrays are stored in row-major order,
+/// and their elements can also be accessed by position, using the SQL subscripts:
+///
+/// ```rust,ignore
+/// #[pg_extern(immutable)]
+/// fn mean(a : Array<f64>) -> Option<f64> {
+///     let values = a.as_slice()?;
+///     Some(values.iter().sum::<f64>() / values.len() as f64)
+/// }
+///
+/// #[pg_extern(immutable)]
+/// fn trace(m : Array<f64>) -> f64 {
+///     m.require_ndims(2);
+///     let (n, lb) = (m.dims()[0], m.lbound()[0]);
+///     (lb..lb + n).filter_map(|i| m.get(&[i, i]) ).sum()
+/// }
+/// ```
+///
+/// The SQL type of Array<T> is T[]: PostgreSQL does not distinguish arrays by their rank, so
+/// functions expecting a given number of dimensions should check it with require_ndims.
+pub struct Array<T> {
+    ptr : *const ArrayType,
+    layout : ElemLayout,
+    _elem : PhantomData<T>
+}
+
+impl<T> Array<T> {
+
+    unsafe fn from_ptr(ptr : *const ArrayType) -> Self {
+        let layout = ElemLayout::of((*ptr).elemtype);
+        Array { ptr, layout, _elem : PhantomData }
+    }
+
+    /// Number of dimensions. Empty arrays have zero dimensions.
+    pub fn ndims(&self) -> usize {
+        unsafe { (*self.ptr).ndim as usize }
+    }
+
+    fn dims_ptr(&self) -> *const c_int {
+        unsafe { (self.ptr as *const u8).add(mem::size_of::<ArrayType>()) as *const c_int }
+    }
+
+    /// Length of each dimension, from the outermost to the innermost.
+    pub fn dims(&self) -> &[i32] {
+        unsafe { slice::from_raw_parts(self.dims_ptr(), self.ndims()) }
+    }
+
+    /// Lower bound (the subscript of the first element) of each dimension. Arrays built by
+    /// the server start at 1, unless explicit bounds were given (e.g. '[0:2]={1,2,3}').
+    pub fn lbound(&self) -> &[i32] {
+        unsafe { slice::from_raw_parts(self.dims_ptr().add(self.ndims()), self.ndims()) }
+    }
+
+    /// Raises an error unless the array has exactly ndims dimensions. Empty arrays are accepted
+    /// for any rank, since the server represents all of them as zero-dimensional arrays.
+    pub fn require_ndims(&self, ndims : usize) {
+        if self.ndims() != ndims && !self.is_empty() {
+            log::Error::raise(&format!(
+                "Expected an array with {} dimension(s), but received an array with {} dimension(s)",
+                ndims,
+                self.ndims()
+            ));
+        }
+    }
+
+    /// Position (in storage order) of the element at the SQL subscripts pos, if they are within
+    /// the array bounds.
+    fn offset_of(&self, pos : &[i32]) -> Option<usize> {
+        if pos.len() != self.ndims() || self.is_empty() {
+            return None;
+        }
+        let mut offset = 0;
+        for ((p, dim), lb) in pos.iter().zip(self.dims()).zip(self.lbound()) {
+            let ix = p.checked_sub(*lb)?;
+            if ix < 0 || ix >= *dim {
+                return None;
+            }
+            offset = offset * (*dim as usize) + ix as usize;
+        }
+        Some(offset)
+    }
+
+    /// Oid of the element type.
+    pub fn elem_type(&self) -> Oid {
+        unsafe { (*self.ptr).elemtype }
+    }
+
+    /// Total number of elements (the product of the dimension lengths).
+    pub fn len(&self) -> usize {
+        let dims = self.dims();
+        if dims.is_empty() {
+            0
+        } else {
+            dims.iter().map(|d| *d as usize ).product()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the array has a null bitmap (which means some elements might be NULL).
+    pub fn has_nulls(&self) -> bool {
+        unsafe { (*self.ptr).dataoffset != 0 }
+    }
+
+    // ARR_NULLBITMAP: A set bit means the element is not null.
+    fn null_bitmap(&self) -> Option<*const u8> {
+        if self.has_nulls() {
+            Some(unsafe { self.dims_ptr().add(2 * self.ndims()) as *const u8 })
+        } else {
+            None
+        }
+    }
+
+    /// Whether the i-th element (in storage order) is NULL.
+    pub fn is_null(&self, i : usize) -> bool {
+        assert!(i < self.len(), "Array index out of bounds");
+        self.null_at(i)
+    }
+
+    fn null_at(&self, i : usize) -> bool {
+        match self.null_bitmap() {
+            Some(bitmap) => unsafe { *bitmap.add(i / 8) & (1 << (i % 8)) == 0 },
+            None => false
+        }
+    }
+
+    // ARR_DATA_PTR
+    fn data_ptr(&self) -> *const u8 {
+        let offset = if self.has_nulls() {
+            unsafe { (*self.ptr).dataoffset as usize }
+        } else {
+            maxalign(mem::size_of::<ArrayType>() + 2 * mem::size_of::<c_int>() * self.ndims())
+        };
+        unsafe { (self.ptr as *const u8).add(offset) }
+    }
+
+    /// Iterates over the elements, in storage order.
+    pub fn iter(&self) -> ArrayIter<'_, T> {
+        ArrayIter { array : self, index : 0, len : self.len(), data : self.data_ptr() }
+    }
+
+}
+
+impl<T : FromDatum> Array<T> {
+
+    /// Returns the element at the given SQL subscripts (one per dimension, counted from the
+    /// lower bounds). As in SQL, subscripts outside the array bounds yield None, as do NULL
+    /// elements.
+    pub fn get(&self, pos : &[i32]) -> Option<T> {
+        let offset = self.offset_of(pos)?;
+        if self.null_at(offset) {
+            return None;
+        }
+
+        // Null-free arrays of fixed-length elements can be addressed directly, since each
+        // element occupies its length rounded up to the type alignment.
+        if !self.has_nulls() && self.layout.len > 0 {
+            let stride = self.layout.align(self.layout.len as usize);
+            let data = unsafe { self.data_ptr().add(offset * stride) };
+            let mut it = ArrayIter { array : self, index : offset, len : offset + 1, data };
+            return it.next().flatten();
+        }
+        self.iter().nth(offset).flatten()
+    }
+
+}
+
+impl<T : FixedWidth> Array<T> {
+
+    /// Returns the elements without copying them, if the array has no NULL elements.
+    pub fn as_slice(&self) -> Option<&[T]> {
+        let data = self.data_ptr();
+        let contiguous = self.layout.len as usize == mem::size_of::<T>() &&
+            (data as usize).is_multiple_of(mem::align_of::<T>());
+        if self.has_nulls() || !contiguous {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts(data as *const T, self.len()) })
+    }
+
+}
+
+impl<T : IntoDatum> Array<T> {
+
+    unsafe fn construct(elems : Vec<Option<Datum>>, dims : &mut [c_int], lbs : &mut [c_int]) -> Self {
+        let elem_type = T::type_oid();
+        let layout = ElemLayout::of(elem_type);
+        let nulls : Vec<bool> = elems.iter().map(|e| e.is_none() ).collect();
+        let mut datums : Vec<Datum> = elems.into_iter().map(|e| e.unwrap_or_default() ).collect();
+        let ptr = ffi::construct_md_array(
+            datums.as_mut_ptr(),
+            nulls.as_ptr() as *mut bool,
+            dims.len() as c_int,
+            dims.as_mut_ptr(),
+            lbs.as_mut_ptr(),
+            elem_type,
+            layout.len as c_int,
+            layout.byval,
+            layout.align
+        );
+        Array { ptr, layout, _elem : PhantomData }
+    }
+
+    /// Builds a new one-dimensional array (allocated via palloc) from the values.
+    pub fn from_vec(values : Vec<T>) -> Self {
+        Self::from_options(values.into_iter().map(Some).collect())
+    }
+
+    /// Builds a new one-dimensional array (allocated via palloc), with a NULL element
+    /// for each None.
+    pub fn from_options(values : Vec<Option<T>>) -> Self {
+        let n = values.len();
+        Self::from_md(values, &[n])
+    }
+
+    /// Builds a two-dimensional array (allocated via palloc) with one row for each vector.
+    /// Raises an error if the rows have different lengths.
+    pub fn from_rows(rows : Vec<Vec<T>>) -> Self {
+        let ncols = rows.first().map(|r| r.len() ).unwrap_or(0);
+        if rows.iter().any(|r| r.len() != ncols ) {
+            log::Error::raise("Multidimensional arrays must have sub-arrays with matching dimensions");
+        }
+        let nrows = rows.len();
+        let values = rows.into_iter().flatten().map(Some).collect();
+        Self::from_md(values, &[nrows, ncols])
+    }
+
+    /// Builds a new array (allocated via palloc) with the given dimension lengths, taking the
+    /// values in row-major order. Subscripts start at 1 in every dimension.
+    pub fn from_md(values : Vec<Option<T>>, dims : &[usize]) -> Self {
+        Self::from_md_with_lbound(values, dims, &vec![1; dims.len()])
+    }
+
+    /// Builds a new array (allocated via palloc) with the given dimension lengths and lower
+    /// bounds, taking the values in row-major order. Raises an error if the number of values
+    /// does not match the dimensions, or if there are more than MAXDIM dimensions.
+    pub fn from_md_with_lbound(values : Vec<Option<T>>, dims : &[usize], lbound : &[i32]) -> Self {
+        if dims.len() > MAXDIM {
+            log::Error::raise(&format!("Number of array dimensions ({}) exceeds the maximum allowed ({})", dims.len(), MAXDIM));
+        }
+        if lbound.len() != dims.len() {
+            log::Error::raise("Array lower bounds must have one entry for each dimension");
+        }
+        let expected : usize = dims.iter().product();
+        if values.len() != expected {
+            log::Error::raise(&format!("Array dimensions {:?} require {} elements, but {} were given", dims, expected, values.len()));
+        }
+        let mut dims : Vec<c_int> = dims.iter().map(|d| *d as c_int ).collect();
+        let mut lbs : Vec<c_int> = lbound.to_vec();
+        let elems = values.into_iter().map(|v| v.and_then(|v| v.into_datum() ) ).collect();
+        unsafe { Self::construct(elems, &mut dims, &mut lbs) }
+    }
+
+}
+
+/// Iterator over the elements of an array, yielding None for NULL elements.
+pub struct ArrayIter<'a, T> {
+    array : &'a Array<T>,
+    index : usize,
+    len : usize,
+
+    // Position of the next non-null element.
+    data : *const u8
+}
+
+impl<'a, T : FromDatum> Iterator for ArrayIter<'a, T> {
+
+    type Item = Option<T>;
+
+    fn next(&mut self) -> Option<Option<T>> {
+        if self.index >= self.len {
+            return None;
+        }
+        let is_null = self.array.null_at(self.index);
+        self.index += 1;
+        if is_null {
+            return Some(None);
+        }
+        let layout = &self.array.layout;
+        unsafe {
+            let ptr = layout.align(self.data as usize) as *const u8;
+
+            // fetch_att and att_addlength_pointer
+            let datum = if layout.byval {
+                match layout.len {
+                    1 => Datum::from(*ptr as usize),
+                    2 => Datum::from((ptr as *const i16).read() as isize as usize),
+                    4 => Datum::from((ptr as *const i32).read() as isize as usize),
+                    _ => Datum::from((ptr as *const i64).read() as usize)
+                }
+            } else {
+                Datum::from_ptr(ptr)
+            };
+            let size = match layout.len {
+                -1 => vla::varsize_any(ptr as *const varlena),
+                -2 => CStr::from_ptr(ptr as *const c_char).to_bytes().len() + 1,
+                len => len as usize
+            };
+            self.data = ptr.add(size);
+            Some(T::from_datum(datum, false))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len - self.index, Some(self.len - self.index))
+    }
+
+}
+
+impl<T : FromDatum> FromDatum for Array<T> {
+
+    fn is_compatible(type_oid : Oid) -> bool {
+        T::is_compatible(unsafe { ffi::get_element_type(type_oid) })
+    }
+
+    unsafe fn from_datum(datum : Datum, is_null : bool) -> Option<Self> {
+        if is_null {
+            return None;
+        }
+
+        // Arrays are always detoasted into a 4-byte header, aligned copy.
+        let ptr = ffi::pg_detoast_datum(datum.as_ptr::<varlena>()) as *const ArrayType;
+        let array = Array::from_ptr(ptr);
+        if !T::is_compatible(array.elem_type()) {
+            log::Error::raise(&format!("Unexpected array element type (oid {})", array.elem_type().0));
+        }
+        Some(array)
+    }
+
+}
+
+impl<T : IntoDatum> IntoDatum for Array<T> {
+
+    fn type_oid() -> Oid {
+        unsafe { ffi::get_array_type(T::type_oid()) }
+    }
+
+    fn into_datum(self) -> Option<Datum> {
+        Some(Datum::from_ptr(self.ptr))
+    }
+
+}
+
+#[test]
+fn element_alignment() {
+    let layout = |align : u8| ElemLayout { len : -1, byval : false, align : align as c_char };
+    assert_eq!(layout(b'c').align(13), 13);
+    assert_eq!(layout(b's').align(13), 14);
+    assert_eq!(layout(b'i').align(13), 16);
+    assert_eq!(layout(b'd').align(17), 24);
+    assert_eq!(maxalign(mem::size_of::<ArrayType>() + 2 * mem::size_of::<c_int>()) % pg_config::MAXIMUM_ALIGNOF, 0);
+}
+
+#[test]
+fn md_positions() {
+    // int4 array '[1:2][0:2]={{1,2,3},{4,5,6}}', with 8-byte words for alignment.
+    let header = mem::size_of::<ArrayType>() / 4;
+    let mut buf = [0i32; 16];
+    buf[1] = 2;
+    buf[3] = super::datum::INT4OID.0 as i32;
+    buf[header..header + 4].copy_from_slice(&[2, 3, 1, 0]);
+    let data = maxalign(4 * (header + 4)) / 4;
+    buf[data..data + 6].copy_from_slice(&[1, 2, 3, 4, 5, 6]);
+    let layout = ElemLayout { len : 4, byval : true, align : b'i' as c_char };
+    let a : Array<i32> = Array { ptr : buf.as_ptr() as *const ArrayType, layout, _elem : PhantomData };
+    assert_eq!(a.ndims(), 2);
+    assert_eq!(a.dims(), &[2, 3]);
+    assert_eq!(a.lbound(), &[1, 0]);
+    assert_eq!(a.len(), 6);
+    assert_eq!(a.get(&[1, 0]), Some(1));
+    assert_eq!(a.get(&[2, 1]), Some(5));
+    assert_eq!(a.get(&[2, 3]), None);
+    assert_eq!(a.get(&[0, 0]), None);
+    assert_eq!(a.get(&[1]), None);
+    assert_eq!(a.as_slice(), Some(&[1, 2, 3, 4, 5, 6][..]));
+}
@@ -1,21 +1,17 @@
-use sqlparser::dialect::PostgreSqlDialect;
-use sqlparser::parser::Parser;
-use sqlparser::tokenizer::{Tokenizer, Token};
-use sqlparser::dialect::keywords::Keyword;
-use std::io;
-use toml;
+#![allow(clippy::useless_format, clippy::needless_borrow)]
+
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use structopt::*;
 use std::path::{PathBuf, Path};
 use std::process::Command;
 use std::ffi::OsStr;
+use pgserver_sql::extension;
 
 #[derive(Debug, Clone)]
 pub struct ExtensionInfo {
-    name : String,
-    description : String,
-    version : String
+    pub name : String,
+    pub description : String,
+    pub version : String
 }
 
 // Reads the current crate's Cargo.toml and extract the .control file required by Postgres.
@@ -26,7 +22,8 @@ pub fn extract_crate_info(toml_path : &Path) -> Result<ExtensionInfo, String> {
     let mut info = String::new();
     let mut f = File::open(toml_path)
         .map_err(|e| format!("Could not read toml file: {}", e) )?;
-    f.read_to_string(&mut info);
+    f.read_to_string(&mut info)
+        .map_err(|e| format!("Could not read toml file: {}", e) )?;
     let v : toml::Value = info.parse()
         .map_err(|e| format!("Could not parse toml file: {}", e) )?;
     let pkg = v.get("package").ok_or(format!(".toml file missing package entry"))?;
@@ -57,14 +54,53 @@ pub fn extract_crate_info(toml_path : &Path) -> Result<ExtensionInfo, String> {
     }
 }
 
-/// Copies SQL definition into target/release/postgres/${extname}-${extversion}.sql
-pub fn write_extension_meta(target_dir : &Path, sql_path : &Path, ext_info : &ExtensionInfo) -> Result<(), String> {
+/// Reads the hand-written SQL fragments at [crate]/sql (if this directory exists), in
+/// lexicographical order of their file names.
+fn read_sql_fragments(crate_dir : &Path) -> Result<Vec<String>, String> {
+    let mut sql_dir = crate_dir.to_path_buf();
+    sql_dir.push("sql");
+    if !sql_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut sql_paths : Vec<_> = sql_dir.read_dir()
+        .map_err(|e| format!("Unable to view content of sql directory: {}", e))?
+        .filter_map(|e| e.ok() )
+        .map(|e| e.path() )
+        .filter(|p| p.extension() == Some(&OsStr::new("sql")))
+        .collect();
+    sql_paths.sort();
+    let mut fragments = Vec::new();
+    for path in sql_paths {
+        let frag = fs::read_to_string(&path)
+            .map_err(|e| format!("Unable to read SQL fragment {}: {}", path.display(), e))?;
+        fragments.push(frag);
+    }
+    Ok(fragments)
+}
+
+/// Generates the extension script from the items annotated at the crate sources (#[pg_extern]
+/// functions and casts), followed by any hand-written fragments at [crate]/sql/*.sql. Returns
+/// the script content.
+pub fn build_extension_sql(crate_dir : &Path, ext_info : &ExtensionInfo) -> Result<String, String> {
+    let mut src_dir = crate_dir.to_path_buf();
+    src_dir.push("src");
+    let items = extension::scan_dir(&src_dir)?;
+    let fragments = read_sql_fragments(crate_dir)?;
+    if items.is_empty() && fragments.is_empty() {
+        return Err(format!("No exported items found at crate sources and no SQL files at [crate]/sql"));
+    }
+    Ok(extension::render_script(&ext_info.name, &items, &fragments))
+}
+
+/// Writes the generated SQL definition into target/release/postgres/${extname}--${extversion}.sql
+pub fn write_extension_meta(target_dir : &Path, crate_dir : &Path, ext_info : &ExtensionInfo) -> Result<(), String> {
     let sql_file_name = format!("{}--{}.sql", ext_info.name, ext_info.version);
     let mut sql_out_path = target_dir.to_path_buf();
     sql_out_path.push(sql_file_name);
-    fs::copy(sql_path, sql_out_path).map_err(|e| format!("{}", e))?;
+    let sql = build_extension_sql(crate_dir, ext_info)?;
+    fs::write(&sql_out_path, sql).map_err(|e| format!("Error writing SQL script: {}", e))?;
 
-    /// Write control definitino into target/release/postgres/${extname}.control
+    // Write control definition into target/release/postgres/${extname}.control
     let mut control_path = target_dir.to_path_buf();
     control_path.push(format!("{}.control", ext_info.name));
     let mut info = String::new();
@@ -84,30 +120,40 @@ pub fn write_extension_meta(target_dir : &Path, sql_path : &Path, ext_info : &Ex
     Ok(())
 }
 
-/// This is the public entry point that will be used to compile the extension.
+/// This is the public entry point that will be used to compile the extension. The crate (which
+/// must have the cdylib crate-type) is built in release mode, and the resulting shared library is
+/// copied into target/release/postgres/${extname}.so, the name module_pathname refers to. Any
+/// extra_flags are forwarded to cargo build.
 pub fn compile_extension(
+    crate_dir : &Path,
     target_dir : &Path,
     ext_info : &ExtensionInfo,
     extra_flags : Option<String>
 ) -> Result<(), String> {
-    let mut src_path = target_dir.to_path_buf();
-    src_path.push(format!("{}.c", ext_info.name));
-    let mut sql_path = target_dir.to_path_buf();
-    sql_path.push(format!("{}--{}.sql", ext_info.name, ext_info.version));
-    let mut src_file = fs::OpenOptions::new()
-        .truncate(true)
-        .create(true)
-        .write(true)
-        .open(&src_path)
-        .map_err(|e| format!("Unable to open src SQL file: {}", e))?;
-    let c_wrapper = build_c_wrapper(&sql_path)?;
-    src_file.write_all(c_wrapper.as_bytes()).map_err(|e| format!("{}", e))?;
-    
-    // Compile object file
-    let obj_name = compile_object(&target_dir, &src_path, &ext_info)?;
-    
-    // Compile so file
-    compile_so(&target_dir, &ext_info, &obj_name[..], extra_flags)?;
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| String::from("cargo"));
+    let mut args = vec!["build", "--release"];
+    if let Some(extra) = extra_flags.as_ref() {
+        args.extend(extra.split_whitespace());
+    }
+    println!("cargo call: {:?}", Command::new(&cargo).args(&args[..]));
+    let status = Command::new(&cargo)
+        .args(&args[..])
+        .current_dir(crate_dir)
+        .status()
+        .map_err(|e| format!("Error invoking cargo to compile the extension: {}", e))?;
+    if !status.success() {
+        return Err(format!("cargo build failed ({})", status));
+    }
+
+    // Hyphens at the package name are replaced by underscores at the library name.
+    let mut lib_path = crate_dir.to_path_buf();
+    lib_path.push("target");
+    lib_path.push("release");
+    lib_path.push(format!("lib{}.so", ext_info.name.replace('-', "_")));
+    let mut so_path = target_dir.to_path_buf();
+    so_path.push(format!("{}.so", ext_info.name));
+    fs::copy(&lib_path, &so_path)
+        .map_err(|e| format!("Unable to copy {:?} (is the crate-type cdylib?): {}", lib_path, e))?;
     Ok(())
 }
 
@@ -115,7 +161,7 @@ pub fn deploy_extension(target_dir : &Path, ext_info : &ExtensionInfo) -> Result
     let pkg_lib_dir = pg_dir("--pkglibdir")?;
     let share_dir = format!("{}/extension", pg_dir("--sharedir")?); //pkglibdir sharedir/extension libdir ??
 
-    let so_name = format!("lib{}.so", ext_info.name);
+    let so_name = format!("{}.so", ext_info.name);
     let sql_name = format!("{}--{}.sql", ext_info.name, ext_info.version);
     let control_name = format!("{}.control", ext_info.name);
     let mut src_so = target_dir.to_path_buf();
@@ -144,79 +190,6 @@ pub fn deploy_extension(target_dir : &Path, ext_info : &ExtensionInfo) -> Result
     Ok(())
 }
 
-// This will eventually be a part of a build script for any Rust crates
-// that are exported as Postgres extensions. The build script for any crates
-// that have the pgserver crate will analyze a top-level sql file containing
-// the SQL definitions and generate the corresponding C wrapper.
-//
-// The actual compilation of the C wrapper will just compile the wrapper
-// C library, which should link againt a static compilation unit containing the whole crate.
-// rustc postgres/parse_native.rs -o postgres/parse_native --extern sqlparser -L target/release/deps
-// This compilation flag can be distributed as a standard Makefile that can be installed on the
-// server via make/make install.
-fn check_c_language(tk_iter : &mut std::vec::Drain<'_, Token>) -> bool {
-    while let Some(fn_tk) = tk_iter.next() {
-        match fn_tk {
-            Token::Word(w) => if w.keyword == Keyword::LANGUAGE {
-                match tk_iter.nth(1) {
-                    Some(Token::Word(w)) => {
-                        if &w.value[..] == "c" || &w.value[..] == "C" {
-                            return true;
-                        } else {
-                            return false;
-                        }
-                    },
-                    _ => { return false; }
-                }
-            },
-            Token::EOF | Token::SemiColon => { return false; },
-            _ => { }
-        }
-    }
-    false
-}
-
-/// Create a C wrapper based on an extension definition SQL file. All functions that are language c at this file
-/// will generate a corresponding macro function definition required by the Postgre server.
-/// let sql = "create function add(a integer, b integer) returns integer as 'file.so', 'function' language c strict";
-fn build_c_wrapper(sql_path : &Path) -> Result<String, String> {
-    let mut sql = String::new();
-    let mut f = File::open(&sql_path)
-        .map_err(|e| format!("Could not read toml file: {}", e) )?;
-    f.read_to_string(&mut sql);
-
-    let dialect = PostgreSqlDialect {};
-    let mut tokenizer = Tokenizer::new(&dialect, &sql);
-    let mut tokens = tokenizer.tokenize().unwrap();
-    let mut tk_iter = tokens.drain(..);
-    let mut fn_names : Vec<String> = Vec::new();
-    while let Some(tk) = tk_iter.next() {
-        match tk {
-            Token::Word(w) => if w.keyword == Keyword::CREATE {
-                match tk_iter.nth(1) {
-                    Some(Token::Word(w)) => if w.keyword == Keyword::FUNCTION {
-                        match tk_iter.nth(1) {
-                            Some(Token::Word(w)) => if check_c_language(&mut tk_iter) {
-                                fn_names.push(w.value.clone());
-                            },
-                            _ => { }
-                        }
-                    },
-                    _ => { }
-                }
-            },
-            _ => { }
-        }
-    }
-
-    let mut c_wrapper = String::new();
-    c_wrapper += &format!("#include \"postgres.h\"\n#include \"fmgr.h\"\n\nPG_MODULE_MAGIC;\n\n");
-    for f in fn_names {
-        c_wrapper += &format!("PG_FUNCTION_INFO_V1({});\n\n", f);
-    }
-    Ok(c_wrapper)
-}
-
 fn pg_dir(flag : &str) -> Result<String, String> {
     let opt_dir = Command::new("pg_config")
         .arg(flag)
@@ -232,67 +205,3 @@ fn pg_dir(flag : &str) -> Result<String, String> {
     println!("Found Postgres directory: {} = {}", flag, dir);
     Ok(dir)
 }
-
-/// Compiles wrapper source into object file, returning its name
-fn compile_object(
-    target_dir : &Path,
-    src_path : &Path,
-    ext_info : &ExtensionInfo
-) -> Result<String, String> {
-    let mut obj_out = target_dir.to_path_buf();
-    obj_out.push(format!("{}.o", ext_info.name));
-    let src_flag = format!("{}", src_path.display());
-    let link_crate = format!("-l{}", ext_info.name);
-    let link_search = "-Ltarget/release";
-    let obj_out_flag = format!("{}", obj_out.display());
-    let include_dir = pg_dir("--includedir-server")?;
-    let pg_include = format!("-I{}", include_dir);
-    let mut o_flags : Vec<&str> = Vec::new();
-    o_flags.extend(["-c", &src_flag[..], "-fPIC", "-o", &obj_out_flag[..]].iter());
-    o_flags.extend([&pg_include[..], &link_search[..], &link_crate[..]].iter());
-    println!("gcc flags (compile .o): {:?}", o_flags);
-    println!("gcc call: {:?}", Command::new("gcc").args(&o_flags[..]));
-    let compile_obj_out = Command::new("gcc")
-        .args(&o_flags[..])
-        .output()
-        .map_err(|e| format!("Error invoking gcc to compile .o file: {}", e))?;
-    if compile_obj_out.status.success() {
-        Ok(obj_out_flag)
-    } else {
-        Err(format!("gcc compilation error: {}", String::from_utf8(compile_obj_out.stderr).unwrap()))
-    }
-}
-
-fn compile_so(
-    target_dir : &Path,
-    ext_info : &ExtensionInfo,
-    obj_out_flag : &str,
-    extra_flags : Option<String>
-) -> Result<(), String> {
-    let mut so_out = target_dir.to_path_buf();
-    so_out.push(format!("lib{}.so", ext_info.name));
-    let so_out_flag = format!("{}", so_out.display());
-    let whole_a = "-Wl,--whole-archive";
-    let static_target = format!("target/release/lib{}.a", ext_info.name);
-    let no_whole_a = "-Wl,--no-whole-archive";
-    let mut so_flags = Vec::new();
-    so_flags.extend([&obj_out_flag[..], "-shared", "-o", &so_out_flag[..]].iter());
-    so_flags.extend([whole_a, &static_target[..], no_whole_a].iter());
-    println!("gcc flags (compile .so): {:?}", so_flags);
-    println!("gcc call: {:?}", Command::new("gcc").args(&so_flags[..]));
-    if let Some(extra) = extra_flags.as_ref() {
-        for flag in extra.split(' ') {
-            so_flags.push(&flag[..]);
-        }
-    }
-    let compile_so_out = Command::new("gcc")
-        .args(&so_flags[..])
-        .output()
-        .map_err(|e| format!("Error inkovking gcc to compile .so file: {}", e))?;
-    if compile_so_out.status.success() {
-        Ok(())
-    } else {
-        Err(format!("gcc compilation error: {}", String::from_utf8(compile_so_out.stderr).unwrap()))
-    }
-}
-
@@ -0,0 +1,249 @@
+use std::mem;
+use super::{ffi, pg_config, Bytea, Text};
+
+/// Word-sized value PostgreSQL uses to pass arguments to and receive results from functions.
+/// Pass-by-value types are stored in the datum itself; everything else (text, bytea, etc.)
+/// is passed as a pointer.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Datum(usize);
+
+impl Datum {
+
+    pub fn from_ptr<T>(ptr : *const T) -> Self {
+        Datum(ptr as usize)
+    }
+
+    pub fn as_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+}
+
+impl From<usize> for Datum {
+    fn from(value : usize) -> Self {
+        Datum(value)
+    }
+}
+
+impl From<Datum> for usize {
+    fn from(datum : Datum) -> Self {
+        datum.0
+    }
+}
+
+/// Object identifier, used by the server to identify types, relations, functions, etc.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Oid(pub u32);
+
+// Oids of built-in types, as defined at pg_type.dat
+pub const BOOLOID : Oid = Oid(16);
+pub const BYTEAOID : Oid = Oid(17);
+pub const INT8OID : Oid = Oid(20);
+pub const INT2OID : Oid = Oid(21);
+pub const INT4OID : Oid = Oid(23);
+pub const TEXTOID : Oid = Oid(25);
+pub const OIDOID : Oid = Oid(26);
+pub const FLOAT4OID : Oid = Oid(700);
+pub const FLOAT8OID : Oid = Oid(701);
+pub const BPCHAROID : Oid = Oid(1042);
+pub const VARCHAROID : Oid = Oid(1043);
+pub const VOIDOID : Oid = Oid(2278);
+
+/// Types that can be read from datums handed by the server (function arguments,
+/// attributes of rows, array elements, etc.).
+pub trait FromDatum : Sized {
+
+    /// Whether values of the SQL type identified by type_oid can be read as Self.
+    fn is_compatible(type_oid : Oid) -> bool;
+
+    /// Reads a datum of a compatible type. Returns None if the datum represents SQL NULL.
+    unsafe fn from_datum(datum : Datum, is_null : bool) -> Option<Self>;
+
+}
+
+/// Types that can be handed to the server as datums (function results, attributes
+/// of rows, array elements, etc.).
+pub trait IntoDatum {
+
+    /// Oid of the SQL type the value is converted to.
+    fn type_oid() -> Oid;
+
+    /// Converts the value into a datum. None represents SQL NULL.
+    fn into_datum(self) -> Option<Datum>;
+
+}
+
+macro_rules! impl_by_value {
+    ($ty:ty, $oid:expr, |$d:ident| $from:expr, |$v:ident| $into:expr) => {
+
+        impl FromDatum for $ty {
+
+            fn is_compatible(type_oid : Oid) -> bool {
+                type_oid == $oid
+            }
+
+            unsafe fn from_datum($d : Datum, is_null : bool) -> Option<Self> {
+                if is_null { None } else { Some($from) }
+            }
+
+        }
+
+        impl IntoDatum for $ty {
+
+            fn type_oid() -> Oid {
+                $oid
+            }
+
+            fn into_datum(self) -> Option<Datum> {
+                let $v = self;
+                Some(Datum($into))
+            }
+
+        }
+    };
+}
+
+// Values narrower than a datum are stored at its low-order bits (sign-extended, for signed
+// types, as the C casts of the Int16GetDatum/Int32GetDatum macros do). Floats are stored by
+// their bit pattern.
+impl_by_value!(bool, BOOLOID, |d| d.0 != 0, |v| v as usize);
+impl_by_value!(i16, INT2OID, |d| d.0 as i16, |v| v as isize as usize);
+impl_by_value!(i32, INT4OID, |d| d.0 as i32, |v| v as isize as usize);
+impl_by_value!(f32, FLOAT4OID, |d| f32::from_bits(d.0 as u32), |v| v.to_bits() as i32 as isize as usize);
+impl_by_value!(Oid, OIDOID, |d| Oid(d.0 as u32), |v| v.0 as usize);
+
+// 8-byte values are passed by value only when datums are 8 bytes wide (USE_FLOAT8_BYVAL);
+// otherwise they are passed as pointers to palloc'd memory.
+unsafe fn read_8_bytes(d : Datum) -> u64 {
+    if pg_config::FLOAT8_BYVAL {
+        d.0 as u64
+    } else {
+        *d.as_ptr::<u64>()
+    }
+}
+
+fn write_8_bytes(v : u64) -> usize {
+    if pg_config::FLOAT8_BYVAL {
+        v as usize
+    } else {
+        unsafe {
+            let ptr = ffi::palloc(mem::size_of::<u64>()) as *mut u64;
+            *ptr = v;
+            ptr as usize
+        }
+    }
+}
+
+impl_by_value!(i64, INT8OID, |d| read_8_bytes(d) as i64, |v| write_8_bytes(v as u64));
+impl_by_value!(f64, FLOAT8OID, |d| f64::from_bits(read_8_bytes(d)), |v| write_8_bytes(v.to_bits()));
+
+impl IntoDatum for () {
+
+    fn type_oid() -> Oid {
+        VOIDOID
+    }
+
+    fn into_datum(self) -> Option<Datum> {
+        Some(Datum(0))
+    }
+
+}
+
+// Varlena arguments are detoasted once, the first time their content is accessed (their length,
+// slices and readers do not need it), and the detoasted copy is kept with the value.
+
+impl FromDatum for Bytea {
+
+    fn is_compatible(type_oid : Oid) -> bool {
+        type_oid == BYTEAOID
+    }
+
+    unsafe fn from_datum(datum : Datum, is_null : bool) -> Option<Self> {
+        if is_null { None } else { Some(Bytea::new(datum.as_ptr())) }
+    }
+
+}
+
+impl IntoDatum for Bytea {
+
+    fn type_oid() -> Oid {
+        BYTEAOID
+    }
+
+    fn into_datum(self) -> Option<Datum> {
+        Some(Datum::from_ptr(self.as_ptr()))
+    }
+
+}
+
+impl FromDatum for Text {
+
+    fn is_compatible(type_oid : Oid) -> bool {
+        type_oid == TEXTOID || type_oid == VARCHAROID || type_oid == BPCHAROID
+    }
+
+    unsafe fn from_datum(datum : Datum, is_null : bool) -> Option<Self> {
+        if is_null { None } else { Some(Text::new(datum.as_ptr())) }
+    }
+
+}
+
+impl IntoDatum for Text {
+
+    fn type_oid() -> Oid {
+        TEXTOID
+    }
+
+    fn into_datum(self) -> Option<Datum> {
+        Some(Datum::from_ptr(self.as_ptr()))
+    }
+
+}
+
+// Option<T> maps SQL NULL to None, allowing functions to receive and return NULL values.
+
+impl<T : FromDatum> FromDatum for Option<T> {
+
+    fn is_compatible(type_oid : Oid) -> bool {
+        T::is_compatible(type_oid)
+    }
+
+    unsafe fn from_datum(datum : Datum, is_null : bool) -> Option<Self> {
+        Some(T::from_datum(datum, is_null))
+    }
+
+}
+
+impl<T : IntoDatum> IntoDatum for Option<T> {
+
+    fn type_oid() -> Oid {
+        T::type_oid()
+    }
+
+    fn into_datum(self) -> Option<Datum> {
+        self.and_then(|v| v.into_datum() )
+    }
+
+}
+
+#[test]
+fn by_value_datums() {
+    fn round_trip<T : FromDatum + IntoDatum + Copy>(v : T) -> T {
+        unsafe { T::from_datum(v.into_datum().unwrap(), false).unwrap() }
+    }
+    assert_eq!((-1i16).into_datum(), Some(Datum(usize::MAX)));
+    assert_eq!(round_trip(-1i16), -1);
+    assert_eq!(round_trip(i32::MIN), i32::MIN);
+    assert_eq!(round_trip(-2.5f32), -2.5);
+    assert!(round_trip(true));
+    assert_eq!(round_trip(Oid(1043)), VARCHAROID);
+    if pg_config::FLOAT8_BYVAL {
+        assert_eq!(round_trip(i64::MIN), i64::MIN);
+        assert_eq!(round_trip(1e-300f64), 1e-300);
+    }
+    assert_eq!(unsafe { i32::from_datum(Datum(0), true) }, None);
+    assert_eq!(unsafe { Option::<i32>::from_datum(Datum(0), true) }, Some(None));
+    assert_eq!(None::<i32>.into_datum(), None);
+}
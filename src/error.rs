@@ -0,0 +1,439 @@
+use std::os::raw::{c_char, c_int, c_void};
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::any::Any;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::sync::Once;
+use std::thread;
+use super::{ffi, log};
+use super::sqlstate::SqlState;
+
+/// An error raised by the server, as captured by xact::subtransaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorData {
+
+    /// Five-character SQLSTATE code (e.g. 23505 for unique_violation).
+    pub sqlstate : String,
+
+    pub message : String,
+
+    pub detail : Option<String>,
+
+    pub hint : Option<String>,
+
+    /// Context lines added while the error propagated (e.g. the SQL statement that failed).
+    pub context : Option<String>,
+
+    /// Objects the error refers to, set by errors such as constraint violations.
+    pub schema_name : Option<String>,
+
+    pub table_name : Option<String>,
+
+    pub column_name : Option<String>,
+
+    pub datatype_name : Option<String>,
+
+    pub constraint_name : Option<String>
+}
+
+impl ErrorData {
+
+    // Reads the error copied by CopyErrorData, and releases the copy.
+    unsafe fn from_raw(data : *mut ffi::ErrorData) -> Self {
+        let string = |s : *const c_char| -> Option<String> {
+            if s.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+            }
+        };
+        let error = ErrorData {
+            sqlstate : unpack_sqlstate((*data).sqlerrcode),
+            message : string((*data).message).unwrap_or_default(),
+            detail : string((*data).detail),
+            hint : string((*data).hint),
+            context : string((*data).context),
+            schema_name : string((*data).schema_name),
+            table_name : string((*data).table_name),
+            column_name : string((*data).column_name),
+            datatype_name : string((*data).datatype_name),
+            constraint_name : string((*data).constraint_name)
+        };
+        ffi::FreeErrorData(data);
+        error
+    }
+
+}
+
+impl ErrorData {
+
+    /// The SQLSTATE as a SqlState variant, unless it is not a standard code.
+    pub fn code(&self) -> Option<SqlState> {
+        SqlState::from_code(&self.sqlstate)
+    }
+
+}
+
+impl fmt::Display for ErrorData {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ErrorData { }
+
+/// The server packs the five characters of a SQLSTATE into six bits each (MAKE_SQLSTATE).
+pub(crate) const fn pack_sqlstate(code : &[u8; 5]) -> c_int {
+    let mut packed = 0;
+    let mut i = 0;
+    while i < 5 {
+        packed |= ((code[i].wrapping_sub(b'0') & 0x3F) as c_int) << (6 * i);
+        i += 1;
+    }
+    packed
+}
+
+fn unpack_sqlstate(code : c_int) -> String {
+    (0..5).map(|i| (((code >> (6 * i)) & 0x3F) as u8 + b'0') as char ).collect()
+}
+
+// ERRCODE_INTERNAL_ERROR (errcodes.h)
+const INTERNAL_ERROR : c_int = pack_sqlstate(b"XX000");
+
+/// A server error caught by guard, carried as the payload of the panic that unwinds the Rust
+/// frames up to the closest boundary (or subtransaction). The copy of the error is released
+/// if the payload is dropped instead.
+pub(crate) struct ServerError(*mut ffi::ErrorData);
+
+// The error is only read (or raised again) at the backend thread that raised it.
+unsafe impl Send for ServerError { }
+
+impl ServerError {
+
+    fn new(data : *mut ffi::ErrorData) -> Self {
+        PENDING.with(|pending| pending.set(pending.get() + 1) );
+        ServerError(data)
+    }
+
+    // Takes the copy of the error out of the payload, so it is not released on drop.
+    fn into_raw(self) -> *mut ffi::ErrorData {
+        PENDING.with(|pending| pending.set(pending.get() - 1) );
+        ManuallyDrop::new(self).0
+    }
+
+    pub(crate) unsafe fn into_data(self) -> ErrorData {
+        ErrorData::from_raw(self.into_raw())
+    }
+
+}
+
+impl Drop for ServerError {
+
+    fn drop(&mut self) {
+        PENDING.with(|pending| pending.set(pending.get() - 1) );
+        unsafe { ffi::FreeErrorData(self.0) };
+    }
+
+}
+
+thread_local! {
+    // Number of server errors caught by guard that were neither raised again, read nor dropped.
+    static PENDING : Cell<usize> = const { Cell::new(0) };
+}
+
+/// Whether a server error caught by guard is unwinding the Rust frames. The transaction has
+/// failed, but is only aborted once the error is raised again (or its subtransaction is rolled
+/// back), so the server state must not be touched meanwhile. The destructors of Spi, Plan,
+/// Cursor, Row and ToastReader do nothing while this holds, and leave their resources to the
+/// abort (which finishes the connections, closes the cursors and releases the descriptors and
+/// memory of the transaction, although kept plans are leaked). Destructors of other values that
+/// call into the server should check it as well.
+pub fn unwinding_error() -> bool {
+    PENDING.with(|pending| pending.get() > 0 )
+}
+
+// Large enough for the sigjmp_buf of the supported platforms (glibc takes 200 bytes at x86_64
+// and 312 at aarch64, and macOS less than that), and as aligned as any of them.
+#[repr(C, align(16))]
+struct SigJmpBuf([u64; 64]);
+
+type SetJmp = unsafe extern "C" fn(env : *mut c_void, savemask : c_int) -> c_int;
+
+// Calls setjmp(buf, 0) and, when it returns 0, f(arg), returning 0 once f returns. When the server
+// jumps back to buf, returns 1 instead. Since setjmp returns twice, which Rust code cannot be
+// compiled to expect, this is written in assembly, and only uses the callee-saved registers (which
+// siglongjmp restores) and its own stack frame after setjmp returns.
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+unsafe extern "C" fn setjmp_call(
+    buf : *mut c_void,
+    f : unsafe extern "C" fn(arg : *mut c_void),
+    arg : *mut c_void,
+    setjmp : SetJmp
+) -> c_int {
+    core::arch::naked_asm!(
+        "push rbp",
+        "mov rbp, rsp",
+        "push r12",
+        "push r13",
+        "mov r12, rsi",
+        "mov r13, rdx",
+        "xor esi, esi",
+        "call rcx",
+        "test eax, eax",
+        "jnz 2f",
+        "mov rdi, r13",
+        "call r12",
+        "xor eax, eax",
+        "jmp 3f",
+        "2:",
+        "mov eax, 1",
+        "3:",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "ret"
+    )
+}
+
+#[cfg(target_arch = "aarch64")]
+#[unsafe(naked)]
+unsafe extern "C" fn setjmp_call(
+    buf : *mut c_void,
+    f : unsafe extern "C" fn(arg : *mut c_void),
+    arg : *mut c_void,
+    setjmp : SetJmp
+) -> c_int {
+    core::arch::naked_asm!(
+        "stp x29, x30, [sp, #-32]!",
+        "mov x29, sp",
+        "stp x19, x20, [sp, #16]",
+        "mov x19, x1",
+        "mov x20, x2",
+        "mov w1, #0",
+        "blr x3",
+        "cbnz w0, 2f",
+        "mov x0, x20",
+        "blr x19",
+        "mov w0, #0",
+        "b 3f",
+        "2:",
+        "mov w0, #1",
+        "3:",
+        "ldp x19, x20, [sp, #16]",
+        "ldp x29, x30, [sp], #32",
+        "ret"
+    )
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("Catching server errors (error::guard) is only implemented for x86_64 and aarch64");
+
+// Calls f, returning the error it raises (if any) instead of letting the server abort the
+// function (the equivalent of the PG_TRY and PG_CATCH macros of elog.h). The error is copied
+// into the memory of the current transaction, so it outlives the contexts released while the
+// Rust frames unwind. Panics of f are propagated. The memory context that was current when
+// catch was called is current again on return.
+fn catch<R, F : FnOnce() -> R>(f : F) -> Result<R, ServerError> {
+
+    struct Call<F, R> {
+        buf : *mut c_void,
+        f : Option<F>,
+        result : Option<thread::Result<R>>
+    }
+
+    // Panics must not unwind into setjmp_call.
+    unsafe extern "C" fn trampoline<R, F : FnOnce() -> R>(arg : *mut c_void) {
+        let call = &mut *(arg as *mut Call<F, R>);
+        ffi::PG_exception_stack = call.buf;
+        let f = call.f.take().unwrap();
+        call.result = Some(panic::catch_unwind(AssertUnwindSafe(f)));
+    }
+
+    let mut buf = MaybeUninit::<SigJmpBuf>::uninit();
+    let mut call = Call { buf : buf.as_mut_ptr() as *mut c_void, f : Some(f), result : None };
+    unsafe {
+        let save_exception_stack = ffi::PG_exception_stack;
+        let save_context_stack = ffi::error_context_stack;
+        let save_memory_context = ffi::CurrentMemoryContext;
+        let jumped = setjmp_call(call.buf, trampoline::<R, F>, &mut call as *mut Call<F, R> as *mut c_void, ffi::sigsetjmp);
+        let mut error = ptr::null_mut();
+        if jumped != 0 {
+            // The error is raised at ErrorContext, which CopyErrorData must not copy into.
+            let top = ffi::TopTransactionContext;
+            ffi::CurrentMemoryContext = if top.is_null() { save_memory_context } else { top };
+            error = ffi::CopyErrorData();
+            ffi::CurrentMemoryContext = save_memory_context;
+            ffi::FlushErrorState();
+        }
+        ffi::PG_exception_stack = save_exception_stack;
+        ffi::error_context_stack = save_context_stack;
+        if !error.is_null() {
+            return Err(ServerError::new(error));
+        }
+    }
+    match call.result.take().unwrap() {
+        Ok(value) => Ok(value),
+        Err(payload) => panic::resume_unwind(payload)
+    }
+}
+
+/// Calls into the server through f (the equivalent of a PG_TRY block). The server reports errors
+/// by jumping straight to the last PG_TRY, past any Rust frames in between, so their destructors
+/// would not run. Under guard, an error raised by f becomes a Rust panic instead, which unwinds
+/// the Rust frames up to the closest boundary, where the error is raised again. Destructors of
+/// these frames run before the transaction is aborted, so they must not call into the server (see
+/// unwinding_error). All server functions this crate calls are guarded; guard is only needed to
+/// call other ones:
+///
+/// ```rust,ignore
+/// extern "C" {
+///     fn pg_backend_pid() -> i32;
+/// }
+///
+/// let pid = error::guard(|| unsafe { pg_backend_pid() });
+/// ```
+pub fn guard<R, F : FnOnce() -> R>(f : F) -> R {
+    match catch(f) {
+        Ok(value) => value,
+        Err(error) => panic::resume_unwind(Box::new(error))
+    }
+}
+
+/// Calls f, raising again the server errors converted to panics by guard once the Rust frames
+/// inside f are unwound. Other panics are reported as errors with SQLSTATE XX000 (internal_error),
+/// with the panic location at the error detail (followed by a backtrace, if the server runs with
+/// RUST_BACKTRACE set), so a bug aborts the statement rather than the backend. Every entry point
+/// generated by the pg_extern, pg_aggregate, pg_trigger and pg_event_trigger attributes runs
+/// under a boundary.
+pub fn boundary<R, F : FnOnce() -> R>(f : F) -> R {
+    HOOK.call_once(|| panic::set_hook(Box::new(record_panic)) );
+    let payload = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => return value,
+        Err(payload) => payload
+    };
+    let error = match payload.downcast::<ServerError>() {
+        Ok(error) => error.into_raw(),
+        Err(payload) => unsafe { raise_panic(payload) }
+    };
+    unsafe { ffi::ReThrowError(error) }
+}
+
+// Panic, as recorded by the panic hook.
+struct Panic {
+    message : String,
+    file : String,
+    line : u32,
+    detail : String
+}
+
+static HOOK : Once = Once::new();
+
+thread_local! {
+    static LAST_PANIC : RefCell<Option<Panic>> = const { RefCell::new(None) };
+
+    // Source files given to errfinish, which keeps the pointer rather than a copy.
+    static FILES : RefCell<HashMap<String, CString>> = RefCell::new(HashMap::new());
+}
+
+/// Source file name to report an error at, which lives as long as the backend.
+pub(crate) fn source_file(file : &str) -> *const c_char {
+    if file.is_empty() {
+        return ptr::null();
+    }
+    FILES.with(|files| {
+        files.borrow_mut()
+            .entry(file.to_string())
+            .or_insert_with(|| CString::new(file.replace('\0', "")).unwrap() )
+            .as_ptr()
+    })
+}
+
+fn panic_message(payload : &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("Box<dyn Any>")
+    }
+}
+
+// Replaces the default hook, which prints the panic to the standard error (the server log, which
+// gets the error report anyway).
+fn record_panic(info : &panic::PanicHookInfo<'_>) {
+    let location = info.location();
+    let mut detail = match location {
+        Some(loc) => format!("panicked at {}:{}:{}", loc.file(), loc.line(), loc.column()),
+        None => String::from("panicked")
+    };
+    let backtrace = Backtrace::capture();
+    if backtrace.status() == BacktraceStatus::Captured {
+        detail += &format!("\nstack backtrace:\n{}", backtrace);
+    }
+    let panic = Panic {
+        message : panic_message(info.payload()),
+        file : location.map(|loc| loc.file().to_string() ).unwrap_or_default(),
+        line : location.map(|loc| loc.line() ).unwrap_or(0),
+        detail
+    };
+    LAST_PANIC.with(|last| *last.borrow_mut() = Some(panic) );
+}
+
+// Equivalent to ereport(ERROR, (errcode(ERRCODE_INTERNAL_ERROR), errmsg(...), errdetail(...))),
+// reported at the location of the panic. Panics resumed without the hook (resume_unwind) are
+// reported by their message only.
+unsafe fn raise_panic(payload : Box<dyn Any + Send>) -> ! {
+    let message = panic_message(&*payload);
+    drop(payload);
+    let panic = LAST_PANIC.with(|last| last.borrow_mut().take() )
+        .filter(|panic| panic.message == message )
+        .unwrap_or(Panic { message, file : String::new(), line : 0, detail : String::new() });
+    let file = source_file(&panic.file);
+    let c_msg = CString::new(panic.message.replace('\0', "")).unwrap();
+    let c_detail = CString::new(panic.detail.replace('\0', "")).unwrap();
+    let line = panic.line as c_int;
+    drop(panic);
+
+    // The messages are copied by errmsg and errdetail, and errfinish does not return.
+    if ffi::errstart(log::ERROR, ptr::null()) {
+        ffi::errcode(INTERNAL_ERROR);
+        ffi::errmsg(b"%s\0".as_ptr() as *const c_char, c_msg.as_ptr());
+        if !c_detail.as_bytes().is_empty() {
+            ffi::errdetail(b"%s\0".as_ptr() as *const c_char, c_detail.as_ptr());
+        }
+    }
+    drop(c_msg);
+    drop(c_detail);
+    ffi::errfinish(file, line, ptr::null());
+    unreachable!()
+}
+
+#[test]
+fn catch_jump() {
+    extern "C" {
+        fn siglongjmp(env : *mut c_void, val : c_int) -> !;
+    }
+    let _server = super::spi::server::SERVER.lock().unwrap();
+    assert_eq!(catch(|| 1 ).ok().unwrap(), 1);
+    let mut unwound = false;
+    let caught = catch(|| -> i32 { unwound = true; unsafe { siglongjmp(ffi::PG_exception_stack, 1) } });
+    assert!(unwound && caught.is_err() && unwinding_error());
+    drop(caught);
+    assert!(!unwinding_error());
+    assert!(unsafe { ffi::PG_exception_stack }.is_null());
+    assert_eq!(catch(|| 2 ).ok().unwrap(), 2);
+}
+
+#[test]
+fn sqlstate() {
+    // ERRCODE_UNIQUE_VIOLATION and ERRCODE_INTERNAL_ERROR
+    assert_eq!(pack_sqlstate(b"23505"), 2 | (3 << 6) | (5 << 12) | (5 << 24));
+    assert_eq!(unpack_sqlstate(pack_sqlstate(b"23505")), "23505");
+    assert_eq!(unpack_sqlstate(INTERNAL_ERROR), "XX000");
+}
@@ -0,0 +1,327 @@
+use std::os::raw::{c_char, c_int, c_long, c_void};
+use super::vla::varlena;
+use super::datum::{Datum, Oid};
+use super::array::ArrayType;
+use super::fmgr::{FunctionCallInfo, FmgrInfo};
+use super::srf::FuncCallContext;
+use super::row::{TupleDesc, HeapTupleData};
+use super::aggregate::Aggref;
+use super::spi::SPITupleTable;
+use super::trigger::RelationData;
+
+/// Opaque pointer to a memory context (MemoryContextData, at memnodes.h).
+pub type MemoryContext = *mut c_void;
+
+/// ABI-compatible with MemoryContextCallback (palloc.h). The callback must be allocated in
+/// the memory context it is registered with, so it lives until the context is reset.
+#[repr(C)]
+pub struct MemoryContextCallback {
+    pub func : Option<unsafe extern "C" fn(arg : *mut c_void)>,
+    pub arg : *mut c_void,
+    pub next : *mut MemoryContextCallback
+}
+
+/// ABI-compatible with ErrorData (elog.h), as copied by CopyErrorData.
+#[repr(C)]
+pub struct ErrorData {
+    pub elevel : c_int,
+    pub output_to_server : bool,
+    pub output_to_client : bool,
+    pub hide_stmt : bool,
+    pub hide_ctx : bool,
+    pub filename : *const c_char,
+    pub lineno : c_int,
+    pub funcname : *const c_char,
+    pub domain : *const c_char,
+    pub context_domain : *const c_char,
+    pub sqlerrcode : c_int,
+    pub message : *mut c_char,
+    pub detail : *mut c_char,
+    pub detail_log : *mut c_char,
+    pub hint : *mut c_char,
+    pub context : *mut c_char,
+    pub backtrace : *mut c_char,
+    pub message_id : *const c_char,
+    pub schema_name : *mut c_char,
+    pub table_name : *mut c_char,
+    pub column_name : *mut c_char,
+    pub datatype_name : *mut c_char,
+    pub constraint_name : *mut c_char,
+    pub cursorpos : c_int,
+    pub internalpos : c_int,
+    pub internalquery : *mut c_char,
+    pub saved_errno : c_int,
+    pub assoc_context : MemoryContext
+}
+
+// SPI_ERROR_ARGUMENT (spi.h)
+pub const SPI_ERROR_ARGUMENT : c_int = -6;
+
+// SPI_ERROR_PARAM (spi.h)
+pub const SPI_ERROR_PARAM : c_int = -7;
+
+/// Opaque pointer to a prepared statement (_SPI_plan, at spi_priv.h).
+pub type SPIPlanPtr = *mut c_void;
+
+/// Opaque pointer to an open cursor (PortalData, at portal.h).
+pub type Portal = *mut c_void;
+
+/// Function using the version-1 calling convention (PGFunction, at fmgr.h).
+pub type PGFunction = unsafe extern "C" fn(fcinfo : FunctionCallInfo) -> Datum;
+
+// Server globals, and the functions that must be called without a guard: the ones that raise (or
+// handle) errors themselves, the ones that fill an error report between errstart and errfinish
+// (which include the variadic ones, that cannot be wrapped), and the ones only passed as a
+// PGFunction.
+extern "C" {
+
+    pub static mut CurrentMemoryContext : MemoryContext;
+
+    pub static mut CurrentResourceOwner : *mut c_void;
+
+    // Set by the server at each SPI call, so they are read with ptr::addr_of!(...).read() at each
+    // use rather than through references.
+    pub static mut SPI_tuptable : *mut SPITupleTable;
+
+    pub static mut SPI_processed : u64;
+
+    pub static mut SPI_result : c_int;
+
+    pub fn FreeErrorData(edata : *mut ErrorData);
+
+    pub static mut PG_exception_stack : *mut c_void;
+
+    pub static mut error_context_stack : *mut c_void;
+
+    pub static mut TopTransactionContext : MemoryContext;
+
+    pub fn CopyErrorData() -> *mut ErrorData;
+
+    pub fn FlushErrorState();
+
+    // Returns twice, so it is never called from Rust, only by error::setjmp_call (glibc
+    // exports it as __sigsetjmp, sigsetjmp being a macro of setjmp.h).
+    #[cfg_attr(target_env = "gnu", link_name = "__sigsetjmp")]
+    pub fn sigsetjmp(env : *mut c_void, savemask : c_int) -> c_int;
+
+    pub fn errstart(elevel : c_int, domain : *const c_char) -> bool;
+
+    pub fn errcode(sqlerrcode : c_int) -> c_int;
+
+    pub fn errmsg(fmt : *const c_char, ...) -> c_int;
+
+    pub fn errdetail(fmt : *const c_char, ...) -> c_int;
+
+    pub fn errhint(fmt : *const c_char, ...) -> c_int;
+
+    pub fn errcontext_msg(fmt : *const c_char, ...) -> c_int;
+
+    pub fn errposition(cursorpos : c_int) -> c_int;
+
+    pub fn err_generic_string(field : c_int, s : *const c_char) -> c_int;
+
+    pub fn errfinish(filename : *const c_char, lineno : c_int, funcname : *const c_char);
+
+    pub fn ReThrowError(edata : *mut ErrorData) -> !;
+
+    pub fn regtypein(fcinfo : FunctionCallInfo) -> Datum;
+
+}
+
+// Declares the server functions at a private module, and a wrapper with the same signature for each
+// of them, which calls it under error::guard. The server errors they raise so become Rust panics,
+// which unwind the Rust frames before the error is raised again (see error::boundary).
+macro_rules! guarded {
+    ($(pub fn $name:ident($($arg:ident : $ty:ty),* $(,)?) $(-> $ret:ty)?;)*) => {
+        mod raw {
+            use super::*;
+
+            extern "C" {
+                $(pub fn $name($($arg : $ty),*) $(-> $ret)?;)*
+            }
+        }
+
+        $(
+            #[allow(non_snake_case, clippy::too_many_arguments)]
+            pub unsafe fn $name($($arg : $ty),*) $(-> $ret)? {
+                super::error::guard(|| raw::$name($($arg),*) )
+            }
+        )*
+    };
+}
+
+// Functions exported by the server. They are resolved when the backend loads the extension,
+// so they are left undefined at the shared library.
+guarded! {
+
+    pub fn MemoryContextAlloc(context : MemoryContext, size : usize) -> *mut c_void;
+
+    pub fn MemoryContextRegisterResetCallback(context : MemoryContext, cb : *mut MemoryContextCallback);
+
+    pub fn palloc(size : usize) -> *mut c_void;
+
+    pub fn pfree(pointer : *mut c_void);
+
+    pub fn pg_detoast_datum(datum : *mut varlena) -> *mut varlena;
+
+    pub fn pg_detoast_datum_packed(datum : *mut varlena) -> *mut varlena;
+
+    pub fn pg_detoast_datum_slice(datum : *mut varlena, first : i32, count : i32) -> *mut varlena;
+
+    pub fn toast_raw_datum_size(value : Datum) -> usize;
+
+    pub fn get_typlenbyvalalign(typid : Oid, typlen : *mut i16, typbyval : *mut bool, typalign : *mut c_char);
+
+    pub fn get_element_type(typid : Oid) -> Oid;
+
+    pub fn get_array_type(typid : Oid) -> Oid;
+
+    pub fn construct_md_array(
+        elems : *mut Datum,
+        nulls : *mut bool,
+        ndims : c_int,
+        dims : *mut c_int,
+        lbs : *mut c_int,
+        elmtype : Oid,
+        elmlen : c_int,
+        elmbyval : bool,
+        elmalign : c_char
+    ) -> *mut ArrayType;
+
+    pub fn init_MultiFuncCall(fcinfo : FunctionCallInfo) -> *mut FuncCallContext;
+
+    pub fn per_MultiFuncCall(fcinfo : FunctionCallInfo) -> *mut FuncCallContext;
+
+    pub fn end_MultiFuncCall(fcinfo : FunctionCallInfo, funcctx : *mut FuncCallContext);
+
+    pub fn get_call_result_type(fcinfo : FunctionCallInfo, result_type_id : *mut Oid, result_desc : *mut TupleDesc) -> c_int;
+
+    pub fn BlessTupleDesc(tupdesc : TupleDesc) -> TupleDesc;
+
+    pub fn heap_form_tuple(tupdesc : TupleDesc, values : *mut Datum, isnull : *mut bool) -> *mut HeapTupleData;
+
+    pub fn HeapTupleHeaderGetDatum(tuple : *mut c_void) -> Datum;
+
+    pub fn lookup_rowtype_tupdesc(type_id : Oid, typmod : i32) -> TupleDesc;
+
+    pub fn DecrTupleDescRefCount(tupdesc : TupleDesc);
+
+    pub fn type_is_rowtype(typid : Oid) -> bool;
+
+    pub fn get_typ_typrelid(typid : Oid) -> Oid;
+
+    pub fn get_func_namespace(funcid : Oid) -> Oid;
+
+    pub fn get_fn_expr_argtype(flinfo : *mut FmgrInfo, argnum : c_int) -> Oid;
+
+    pub fn getBaseType(typid : Oid) -> Oid;
+
+    pub fn quote_qualified_identifier(qualifier : *const c_char, ident : *const c_char) -> *mut c_char;
+
+    pub fn DirectFunctionCall1Coll(func : PGFunction, collation : Oid, arg1 : Datum) -> Datum;
+
+    pub fn heap_modify_tuple_by_cols(
+        tuple : *mut HeapTupleData,
+        tupdesc : TupleDesc,
+        ncols : c_int,
+        repl_cols : *mut c_int,
+        repl_values : *mut Datum,
+        repl_isnull : *mut bool
+    ) -> *mut HeapTupleData;
+
+    pub fn heap_copytuple(tuple : *mut HeapTupleData) -> *mut HeapTupleData;
+
+    pub fn IsBinaryCoercible(srctype : Oid, targettype : Oid) -> bool;
+
+    pub fn SPI_fnumber(tupdesc : TupleDesc, fname : *const c_char) -> c_int;
+
+    pub fn SPI_fname(tupdesc : TupleDesc, fnumber : c_int) -> *mut c_char;
+
+    pub fn SPI_gettypeid(tupdesc : TupleDesc, fnumber : c_int) -> Oid;
+
+    pub fn SPI_getbinval(tuple : *mut HeapTupleData, tupdesc : TupleDesc, fnumber : c_int, isnull : *mut bool) -> Datum;
+
+    pub fn SPI_connect() -> c_int;
+
+    pub fn SPI_finish() -> c_int;
+
+    pub fn SPI_execute_with_args(
+        src : *const c_char,
+        nargs : c_int,
+        argtypes : *mut Oid,
+        values : *mut Datum,
+        nulls : *const c_char,
+        read_only : bool,
+        tcount : c_long
+    ) -> c_int;
+
+    pub fn SPI_prepare(src : *const c_char, nargs : c_int, argtypes : *mut Oid) -> SPIPlanPtr;
+
+    pub fn SPI_keepplan(plan : SPIPlanPtr) -> c_int;
+
+    pub fn SPI_freeplan(plan : SPIPlanPtr) -> c_int;
+
+    pub fn SPI_getargcount(plan : SPIPlanPtr) -> c_int;
+
+    pub fn SPI_getargtypeid(plan : SPIPlanPtr, arg_index : c_int) -> Oid;
+
+    pub fn SPI_execute_plan(
+        plan : SPIPlanPtr,
+        values : *mut Datum,
+        nulls : *const c_char,
+        read_only : bool,
+        tcount : c_long
+    ) -> c_int;
+
+    pub fn SPI_cursor_open(
+        name : *const c_char,
+        plan : SPIPlanPtr,
+        values : *mut Datum,
+        nulls : *const c_char,
+        read_only : bool
+    ) -> Portal;
+
+    pub fn SPI_cursor_open_with_args(
+        name : *const c_char,
+        src : *const c_char,
+        nargs : c_int,
+        argtypes : *mut Oid,
+        values : *mut Datum,
+        nulls : *const c_char,
+        read_only : bool,
+        cursor_options : c_int
+    ) -> Portal;
+
+    pub fn SPI_cursor_fetch(portal : Portal, forward : bool, count : c_long);
+
+    pub fn SPI_cursor_close(portal : Portal);
+
+    pub fn SPI_freetuptable(tuptable : *mut SPITupleTable);
+
+    pub fn SPI_result_code_string(code : c_int) -> *const c_char;
+
+    pub fn CreateTupleDescCopy(tupdesc : TupleDesc) -> TupleDesc;
+
+    pub fn GetCommandTagName(tag : c_int) -> *const c_char;
+
+    pub fn SPI_getrelname(rel : *mut RelationData) -> *mut c_char;
+
+    pub fn SPI_getnspname(rel : *mut RelationData) -> *mut c_char;
+
+    pub fn get_rel_name(relid : Oid) -> *mut c_char;
+
+    pub fn get_namespace_name(nspid : Oid) -> *mut c_char;
+
+    pub fn AggCheckCallContext(fcinfo : FunctionCallInfo, aggcontext : *mut MemoryContext) -> c_int;
+
+    pub fn AggGetAggref(fcinfo : FunctionCallInfo) -> *mut Aggref;
+
+    pub fn get_equality_op_for_ordering_op(opno : Oid, reverse : *mut bool) -> Oid;
+
+    pub fn BeginInternalSubTransaction(name : *const c_char);
+
+    pub fn ReleaseCurrentSubTransaction();
+
+    pub fn RollbackAndReleaseCurrentSubTransaction();
+
+}
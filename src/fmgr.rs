@@ -0,0 +1,202 @@
+use std::os::raw::{c_char, c_int, c_void};
+use std::mem;
+use std::cell::RefCell;
+#[cfg(pg_ge_18)]
+use std::ptr;
+use super::pg_config;
+use super::datum::{Datum, Oid, FromDatum, IntoDatum};
+use super::{ffi, log};
+use super::sqlstate::SqlState;
+
+/// ABI-compatible with NullableDatum (fmgr.h)
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct NullableDatum {
+    pub value : Datum,
+    pub isnull : bool
+}
+
+/// ABI-compatible with FmgrInfo (fmgr.h), the lookup information the server keeps for each
+/// function call site. fn_extra is free for the function to use across calls at the same
+/// site (set-returning functions keep their FuncCallContext there).
+#[repr(C)]
+pub struct FmgrInfo {
+    pub fn_addr : *mut c_void,
+    pub fn_oid : Oid,
+    pub fn_nargs : i16,
+    pub fn_strict : bool,
+    pub fn_retset : bool,
+    pub fn_stats : u8,
+    pub fn_extra : *mut c_void,
+    pub fn_mcxt : *mut c_void,
+    pub fn_expr : *mut c_void
+}
+
+/// ABI-compatible with FunctionCallInfoBaseData (fmgr.h), which is how the server hands arguments
+/// to functions using the version-1 calling convention. The args field is a flexible array
+/// member with nargs elements.
+#[repr(C)]
+pub struct FunctionCallInfoBaseData {
+    pub flinfo : *mut FmgrInfo,
+    pub context : *mut c_void,
+    pub resultinfo : *mut c_void,
+    pub fncollation : Oid,
+    pub isnull : bool,
+    pub nargs : i16,
+    pub args : [NullableDatum; 0]
+}
+
+pub type FunctionCallInfo = *mut FunctionCallInfoBaseData;
+
+/// ABI-compatible with Pg_finfo_record (fmgr.h). The server calls the pg_finfo_[symbol]
+/// function to learn which calling convention [symbol] uses.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct Pg_finfo_record {
+    pub api_version : c_int
+}
+
+/// Record returned by the pg_finfo_* functions generated by #[pg_extern].
+pub static FINFO_V1 : Pg_finfo_record = Pg_finfo_record { api_version : 1 };
+
+/// ABI-compatible with Pg_magic_struct (fmgr.h). The server refuses to load a library whose
+/// magic block differs from its own, which guards against loading extensions built against
+/// another major version or build configuration.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct Pg_magic_struct {
+    pub len : c_int,
+    pub version : c_int,
+    pub funcmaxargs : c_int,
+    pub indexmaxkeys : c_int,
+    pub namedatalen : c_int,
+    pub float8byval : c_int,
+    #[cfg(pg_ge_15)]
+    pub abi_extra : [c_char; 32],
+    #[cfg(pg_ge_18)]
+    pub name : *const c_char,
+    #[cfg(pg_ge_18)]
+    pub module_version : *const c_char
+}
+
+// The magic block only holds null name/version pointers, which are never written to.
+unsafe impl Sync for Pg_magic_struct { }
+
+#[cfg(pg_ge_15)]
+const fn abi_extra() -> [c_char; 32] {
+    // Same as FMGR_ABI_EXTRA for the community server
+    let tag = b"PostgreSQL";
+    let mut extra = [0 as c_char; 32];
+    let mut i = 0;
+    while i < tag.len() {
+        extra[i] = tag[i] as c_char;
+        i += 1;
+    }
+    extra
+}
+
+/// Magic block returned by the Pg_magic_func generated by pg_module_magic!().
+pub static PG_MAGIC_DATA : Pg_magic_struct = Pg_magic_struct {
+    len : mem::size_of::<Pg_magic_struct>() as c_int,
+    version : pg_config::PG_VERSION_NUM / 100,
+    funcmaxargs : pg_config::FUNC_MAX_ARGS,
+    indexmaxkeys : pg_config::INDEX_MAX_KEYS,
+    namedatalen : pg_config::NAMEDATALEN,
+    float8byval : pg_config::FLOAT8_BYVAL as c_int,
+    #[cfg(pg_ge_15)]
+    abi_extra : abi_extra(),
+    #[cfg(pg_ge_18)]
+    name : ptr::null(),
+    #[cfg(pg_ge_18)]
+    module_version : ptr::null()
+};
+
+/// Declares the Pg_magic_func symbol the server requires from every loadable module (the
+/// equivalent of PG_MODULE_MAGIC). Invoke it once, at the root of the extension crate:
+///
+/// ```rust,ignore
+/// pgserver::pg_module_magic!();
+/// ```
+#[macro_export]
+macro_rules! pg_module_magic {
+    () => {
+        #[no_mangle]
+        #[doc(hidden)]
+        #[allow(non_snake_case)]
+        pub extern "C" fn Pg_magic_func() -> &'static $crate::fmgr::Pg_magic_struct {
+            &$crate::fmgr::PG_MAGIC_DATA
+        }
+    };
+}
+
+thread_local! {
+    // Calls to the functions of the extension in progress, the innermost last (a function can
+    // call other functions of the extension through SPI).
+    static CALLS : RefCell<Vec<FunctionCallInfo>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Calls f on behalf of the function called through fcinfo, which is the current call until f
+/// returns or unwinds. Every entry point generated by the pg_extern, pg_aggregate, pg_trigger
+/// and pg_event_trigger attributes runs its body this way.
+pub fn call<R, F : FnOnce() -> R>(fcinfo : FunctionCallInfo, f : F) -> R {
+
+    struct Exit;
+
+    impl Drop for Exit {
+        fn drop(&mut self) {
+            CALLS.with(|calls| calls.borrow_mut().pop() );
+        }
+    }
+
+    CALLS.with(|calls| calls.borrow_mut().push(fcinfo) );
+    let _exit = Exit;
+    f()
+}
+
+/// Call information of the innermost function of the extension being called, if any.
+pub fn current_call() -> Option<FunctionCallInfo> {
+    CALLS.with(|calls| calls.borrow().last().copied() )
+}
+
+/// Whether the n-th argument is SQL NULL.
+pub unsafe fn arg_is_null(fcinfo : FunctionCallInfo, n : usize) -> bool {
+    (*(*fcinfo).args.as_ptr().add(n)).isnull
+}
+
+/// Datum of the n-th argument, as passed by the server.
+pub unsafe fn arg_datum(fcinfo : FunctionCallInfo, n : usize) -> Datum {
+    (*(*fcinfo).args.as_ptr().add(n)).value
+}
+
+/// Reads the n-th argument from the call information. Raises an error if the argument is
+/// SQL NULL (which the server never passes to functions declared strict), or if the call
+/// expression is known and gives the argument a type T cannot be read from (domains are
+/// checked by their base type).
+pub unsafe fn arg<T : FromDatum>(fcinfo : FunctionCallInfo, n : usize) -> T {
+    let flinfo = (*fcinfo).flinfo;
+    if !flinfo.is_null() && !(*flinfo).fn_expr.is_null() {
+        let type_oid = ffi::get_fn_expr_argtype(flinfo, n as c_int);
+        if type_oid != Oid(0) && !T::is_compatible(ffi::getBaseType(type_oid)) {
+            log::ErrorReport::new(&format!("Argument {} has an incompatible type (oid {})", n + 1, type_oid.0))
+                .errcode(SqlState::DatatypeMismatch)
+                .raise();
+        }
+    }
+    let arg = *(*fcinfo).args.as_ptr().add(n);
+    match T::from_datum(arg.value, arg.isnull) {
+        Some(value) => value,
+        None => log::Error::raise(&format!("Argument {} must not be null", n + 1))
+    }
+}
+
+/// Converts a returned value into the result datum, flagging the result as
+/// SQL NULL if the value converts to None.
+pub unsafe fn ret<T : IntoDatum>(fcinfo : FunctionCallInfo, value : T) -> Datum {
+    match value.into_datum() {
+        Some(datum) => datum,
+        None => {
+            (*fcinfo).isnull = true;
+            Datum::default()
+        }
+    }
+}
@@ -1,14 +1,18 @@
-#![feature(vec_into_raw_parts)]
-#![feature(c_variadic)]
-#![feature(never_type)]
+//! Types and attributes to write PostgreSQL extensions in Rust.
+//!
+//! Building requires the pg_config of the target server (the first at PATH, or the one set by
+//! the PG_CONFIG environment variable), whose client headers hold the constants the server checks
+//! when it loads the extension. Everything else (including the varlena header macros of postgres.h,
+//! and the sigsetjmp call PG_TRY relies on, see error::guard) is implemented in Rust, so neither
+//! the server headers nor a C compiler are needed.
+
+#![allow(clippy::missing_safety_doc)]
 
 use std::slice;
-use std::os::raw::c_char;
 use std::fmt;
 use std::convert::{self, TryInto};
-use std::mem;
-use std::ffi::CString;
 use std::ptr;
+use std::cell::Cell;
 
 /// Enumeration that wraps PostgreSQL logging via raise
 pub mod log;
@@ -16,56 +20,118 @@ pub mod log;
 /// Utilities to build PostgreSQL extensions
 pub mod build;
 
-/// Bindgen-generated code to represent variable-length arrays allocated by Postgres.
-mod vla;
+/// Conversion between Rust values and the datums exchanged with the server.
+pub mod datum;
 
-use vla::varlena;
+/// PostgreSQL arrays, readable as Rust slices and iterators.
+pub mod array;
+
+/// Composite values (rows): Arguments read by attribute, and results built from Rust tuples and structs.
+pub mod row;
+
+/// Set-returning functions, which produce one row per value of a Rust iterator.
+pub mod srf;
+
+/// User-defined aggregates.
+pub mod aggregate;
+
+/// Trigger and event trigger functions, with access to the rows (or the DDL commands) the
+/// trigger fired for.
+pub mod trigger;
 
-// Available at link time from the pg_helper.c module (which in turn relies on C calls which
-// will be available only at runtime at the server).
-extern "C" {
+/// Server Programming Interface: Runs SQL queries and commands from within functions.
+pub mod spi;
 
-    fn read_from_pg(arg : *const varlena) -> ByteSlice;
+/// Errors raised by the server, captured as Rust values.
+pub mod error;
 
-    fn palloc_varlena(sz : usize) -> *const varlena;
+/// Standard SQLSTATE error codes.
+pub mod sqlstate;
 
-    fn copy_to_pg(s : ByteSlice) -> *const varlena;
+/// Subtransactions, which let functions recover from the errors raised by the server.
+pub mod xact;
 
-    fn bytes_ptr(t : *const varlena) -> *const u8;
+/// Version-1 calling convention structures used by the entry points #[pg_extern] generates.
+pub mod fmgr;
 
-    fn bytes_len(t : *const varlena) -> usize;
+/// Compile-time constants of the server the extension is built against.
+pub mod pg_config;
 
-    fn report(kind : i32, msg : *const c_char);
+pub use pgserver_macros::{pg_extern, pg_aggregate, pg_trigger, pg_event_trigger, PgRow, PgComposite};
+
+/// Bindgen-generated code to represent variable-length arrays allocated by Postgres,
+/// and the header manipulation macros from postgres.h.
+mod vla;
+
+/// Declarations of the server functions called by this crate.
+mod ffi;
+
+/// Incremental access to large (TOASTed) bytea and text values.
+pub mod toast;
+
+use toast::ToastReader;
+
+use vla::varlena;
 
+/// Allocates a varlena with room for sz bytes of data via palloc, and writes its header.
+fn palloc_varlena(sz : usize) -> *mut varlena {
+    unsafe {
+        let data = ffi::palloc(vla::VARHDRSZ + sz) as *mut varlena;
+        vla::set_varsize(data, vla::VARHDRSZ + sz);
+        data
+    }
 }
 
 /// PostgreSQL raw byte array (bytea). Allows the user to write functions which
 /// take Bytea as arguments (mapping to a bytea field at the SQL definition).
-/// This structure just wraps a palloc-allocated pointer, so returning it from
-/// functions is the same as returning *const varlena.
-#[derive(Clone, Copy, Debug)]
-#[repr(C)]
-pub struct Bytea(*const varlena);
+/// This structure wraps a palloc-allocated pointer. Arguments stored compressed or
+/// out-of-line (TOAST) are fetched transparently the first time their content is accessed,
+/// and the fetched copy is kept for later accesses (and returned in place of the
+/// original value, if the content was modified).
+#[derive(Clone, Debug)]
+pub struct Bytea {
+
+    // Value as handed by the server, possibly toasted.
+    raw : *const varlena,
+
+    // Detoasted value, or null if the content has not been accessed yet.
+    data : Cell<*const varlena>
+
+}
 
 impl Bytea {
 
+    pub(crate) fn new(raw : *const varlena) -> Self {
+        Bytea { raw, data : Cell::new(ptr::null()) }
+    }
+
+    // Pointer to the content, detoasting the value on the first call.
+    fn data(&self) -> *const varlena {
+        if self.data.get().is_null() {
+            self.data.set(detoast(self.raw));
+        }
+        self.data.get()
+    }
+
+    // Pointer to the value as it should be handed back to the server.
+    pub(crate) fn as_ptr(&self) -> *const varlena {
+        if self.data.get().is_null() { self.raw } else { self.data.get() }
+    }
+
     /// Allocates a buffer without initializing its contents. You can copy
     /// data into the buffer with:
     ///
-    /// ```rust
+    /// ```rust,ignore
     /// let mut b = Bytea::palloc(5);
     /// b.as_mut().copy_from_slice(&[0u8, 0u8, 0u8, 1u8, 1u8]);
     /// ```
     ///
     /// Make sure the passed slice has the exact same size that was allocated,
-    /// at the penalty of Rust throwing a panic (if this panic is uncaught via
-    /// catch_unwind, the server might crash and cut the connection). Use Bytea::from
+    /// at the penalty of Rust throwing a panic (which aborts the statement with
+    /// an internal error, see error::boundary). Use Bytea::from
     /// to allocate exactly the ammount of data you will need directly from a &[u8] or Vec<u8>.
     pub fn palloc(sz : usize) -> Self {
-        unsafe {
-            let vl_ptr : *const varlena = palloc_varlena(sz);
-            Bytea(vl_ptr)
-        }
+        Bytea::new(palloc_varlena(sz))
     }
 
     /// Copies the content of data into a new buffer allocated via palloc
@@ -85,6 +151,28 @@ impl Bytea {
         std::str::from_utf8_mut(self.as_mut()).ok()
     }
 
+    /// Number of bytes of the (uncompressed) value. Unlike self.as_ref().len(), this
+    /// does not require fetching or decompressing toasted values.
+    pub fn len(&self) -> usize {
+        toast::raw_len(self.raw)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies up to len bytes starting at offset into a new buffer. For values stored compressed
+    /// or out-of-line, only the part of the value required to produce the slice is fetched.
+    pub fn slice(&self, offset : usize, len : usize) -> Bytea {
+        toast::slice(self.raw, offset, len)
+    }
+
+    /// Returns a reader (implementing std::io::Read and std::io::Seek) that fetches
+    /// the value in chunks, without ever materializing it completely.
+    pub fn reader(&self) -> ToastReader {
+        ToastReader::new(self.raw)
+    }
+
 }
 
 /// PostgreSQL text type. Just wraps a bytea, but adds the guarantee that the underlying
@@ -94,19 +182,32 @@ impl Bytea {
 /// To acquire a text, allocate a generic buffer via let b = Bytea::palloc(n), then write a valid UTF-8 to the buffer
 /// via b.as_mut().copy_from_slice(&str.as_bytes()); Then wrap the result from the fallible conversion via
 /// let txt = b.try_into().unwrap();
-#[derive(Clone, Copy, Debug)]
-#[repr(C)]
-pub struct Text(*const varlena);
+#[derive(Clone, Debug)]
+pub struct Text(Bytea);
 
 impl Text {
 
+    pub(crate) fn new(raw : *const varlena) -> Self {
+        Text(Bytea::new(raw))
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const varlena {
+        self.0.as_ptr()
+    }
+
     /// Allocates a buffer and copies the slice contents into it.
     pub fn from(content : &str) -> Self {
-        let mut txt_bytes = Bytea::palloc(content.as_bytes().len());
+        let mut txt_bytes = Bytea::palloc(content.len());
         txt_bytes.as_mut().copy_from_slice(content.as_bytes());
         txt_bytes.try_into().unwrap()
     }
 
+    /// Returns a reader over the UTF-8 bytes of the value, fetching it in chunks
+    /// (see Bytea::reader).
+    pub fn reader(&self) -> ToastReader {
+        self.0.reader()
+    }
+
 }
 
 impl convert::TryInto<Text> for Bytea {
@@ -115,7 +216,7 @@ impl convert::TryInto<Text> for Bytea {
 
     fn try_into(self) -> Result<Text, ()> {
         if self.as_str().is_some() {
-            Ok(Text(self.0))
+            Ok(Text(self))
         } else {
             Err(())
         }
@@ -132,61 +233,54 @@ pub type BpChar = Text;
 
 pub type VarChar = Text;
 
-// ABI-compatible struct with ByteSlice from pg_helper.c
-#[repr(C)]
-struct ByteSlice  {
-    data : *const u8,
-    len : usize
-}
-
-impl From<Vec<u8>> for ByteSlice {
-    fn from(v : Vec<u8>) -> Self {
-        let (data, _, cap) = unsafe{ v.into_raw_parts() };
-        ByteSlice{ data, len : cap }
+/// Returns a pointer to a varlena whose data can be read in place. Values stored out-of-line
+/// (TOAST pointers) or compressed are fetched and decompressed into a palloc'd copy, which lives
+/// until the current memory context is reset. Values with short (1-byte) headers are returned as-is,
+/// since VARDATA_ANY/VARSIZE_ANY already account for them.
+fn detoast(bytes : *const varlena) -> *const varlena {
+    unsafe {
+        if vla::is_extended(bytes) {
+            ffi::pg_detoast_datum_packed(bytes as *mut _)
+        } else {
+            bytes
+        }
     }
 }
 
 fn bytes_to_slice<'a>(bytes : *const varlena) -> &'a [u8] {
-    unsafe{ slice::from_raw_parts(bytes_ptr(bytes) as *mut _, bytes_len(bytes)) }
+    unsafe{ slice::from_raw_parts(vla::vardata_any(bytes), vla::varsize_any_exhdr(bytes)) }
 }
 
 fn bytes_to_slice_mut<'a>(bytes : *mut varlena) -> &'a mut [u8] {
-    unsafe{ slice::from_raw_parts_mut(bytes_ptr(bytes) as *mut _, bytes_len(bytes)) }
-}
-
-fn utf8_to_str<'a>(bytes : *const varlena) -> &'a str {
-    unsafe{ std::str::from_utf8(bytes_to_slice(bytes as *const varlena)).unwrap() }
-}
-
-fn utf8_to_str_mut<'a>(bytes : *mut varlena) -> &'a mut str {
-    unsafe{ std::str::from_utf8_mut(bytes_to_slice_mut(bytes)).unwrap() }
+    unsafe{ slice::from_raw_parts_mut(vla::vardata_any(bytes) as *mut _, vla::varsize_any_exhdr(bytes)) }
 }
 
 impl AsRef<[u8]> for Bytea {
 
     fn as_ref(&self) -> &[u8] {
-        bytes_to_slice(self.0 as *const _)
+        bytes_to_slice(self.data())
     }
 }
 
 impl AsMut<[u8]> for Bytea {
 
+    // Writes to the detoasted copy, if the value was toasted, which is what into_datum returns.
     fn as_mut(&mut self) -> &mut [u8] {
-        bytes_to_slice_mut(self.0 as *mut _)
+        bytes_to_slice_mut(self.data() as *mut _)
     }
 }
 
 impl AsRef<str> for Text {
 
     fn as_ref(&self) -> &str {
-        utf8_to_str(self.0 as *const _)
+        std::str::from_utf8(self.0.as_ref()).unwrap()
     }
 }
 
 impl AsMut<str> for Text {
 
     fn as_mut(&mut self) -> &mut str {
-        utf8_to_str_mut(self.0 as *mut _)
+        std::str::from_utf8_mut(self.0.as_mut()).unwrap()
     }
 }
 
@@ -201,32 +295,22 @@ impl From<String> for Text {
 
 impl From<Vec<u8>> for Bytea {
     fn from(v : Vec<u8>) -> Self {
-        unsafe {
-            let vl_ptr : *const varlena = copy_bytes_to_pg(v);
-            Self(vl_ptr)
-        }
+        Self::new(copy_bytes_to_pg(&v[..]))
     }
 }
 
-/// Copies data from s into a buffer allocated via palloc, returning the
+/// Copies data into a buffer allocated via palloc, returning the
 /// newly-allocated data pointer.
-fn copy_bytes_to_pg(data_vec : Vec<u8>) -> *const varlena {
-    // Recover points to original data and forget to clear it for now
-    let (data, len, cap) = data_vec.into_raw_parts();
-    let bs = ByteSlice{ data, len };
-
+fn copy_bytes_to_pg(data : &[u8]) -> *const varlena {
+    let dst = palloc_varlena(data.len());
     unsafe {
-        // Space is allocated via palloc here and data is copied into it
-        let bytes_ptr = copy_to_pg(bs);
-
-        // Re-build original content so Rust-allocated data can be dropped
-        let _ = Vec::from_raw_parts(data, len, cap);
-
-        bytes_ptr
+        ptr::copy_nonoverlapping(data.as_ptr(), vla::vardata_any(dst) as *mut u8, data.len());
     }
+    dst
 }
 
 #[test]
+#[allow(clippy::needless_as_bytes)]
 fn test() {
     let txt = "Hello";
     //let bytes = Bytea::palloc(txt.as_bytes().len());
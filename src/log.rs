@@ -1,20 +1,47 @@
 use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::panic::Location;
+use std::ptr;
+use super::{ffi, error};
+use super::sqlstate::SqlState;
+
+// Severity levels from elog.h. WARNING_CLIENT_ONLY was inserted before ERROR in PostgreSQL 14.
+const NOTICE : c_int = 18;
+
+const WARNING : c_int = 19;
+
+#[cfg(not(pg_ge_14))]
+pub(crate) const ERROR : c_int = 20;
+
+#[cfg(pg_ge_14)]
+pub(crate) const ERROR : c_int = 21;
+
+// Equivalent to ereport(level, (errmsg("%s", msg))). The message is copied by errmsg, so it
+// is released before errfinish, which does not return for levels >= ERROR (the error becomes a
+// panic under the guard, see error::guard).
+fn report(level : c_int, msg : &str) {
+    let c_msg = CString::new(msg.replace('\0', "")).unwrap();
+    unsafe {
+        if ffi::errstart(level, ptr::null()) {
+            ffi::errmsg(b"%s\0".as_ptr() as *const _, c_msg.as_ptr());
+            drop(c_msg);
+            error::guard(|| ffi::errfinish(ptr::null(), 0, ptr::null()) );
+        }
+    }
+}
 
 /// Emits a log using PostgreSQL raise mechanism.
 ///
-/// ```rust
-/// log::Notice.raise("Just a friendly notice - carry on");
-/// log::Warning.raise("An important warning - but carry on");
-/// log::Error.raise("Error executing the function - stop here");
+/// ```rust,ignore
+/// log::Notice::raise("Just a friendly notice - carry on");
+/// log::Warning::raise("An important warning - but carry on");
+/// log::Error::raise("Error executing the function - stop here");
 /// ```
-
 pub struct Notice;
 
 impl Notice {
     pub fn raise(msg : &str) {
-        let c_msg = CString::new(msg).unwrap();
-        let msg_raw = c_msg.into_raw();
-        unsafe { super::report(18, msg_raw) }
+        report(NOTICE, msg)
     }
 }
 
@@ -22,24 +49,192 @@ pub struct Warning;
 
 impl Warning {
     pub fn raise(msg : &str) {
-        let c_msg = CString::new(msg).unwrap();
-        let msg_raw = c_msg.into_raw();
-        unsafe { super::report(19, msg_raw) }
+        report(WARNING, msg)
     }
 }
 
 /// Error differs from Warning and Notice in that it stops execution of
 /// the current function, so it can be called at the return point
-/// of the final expression of a function.
+/// of the final expression of a function. The Rust frames are unwound
+/// (running their destructors) before the server aborts the statement.
+/// Use ErrorReport to raise errors with a SQLSTATE code and the other
+/// optional fields.
 pub struct Error;
 
 impl Error {
+    #[track_caller]
     pub fn raise(msg : &str) -> ! {
-        let c_msg = CString::new(msg).unwrap();
-        let msg_raw = c_msg.into_raw();
-        unsafe { super::report(20, msg_raw) }
+        ErrorReport::new(msg).raise()
+    }
+}
+
+// Field codes of err_generic_string (PG_DIAG_* at postgres_ext.h)
+const PG_DIAG_SCHEMA_NAME : c_int = b's' as c_int;
+
+const PG_DIAG_TABLE_NAME : c_int = b't' as c_int;
+
+const PG_DIAG_COLUMN_NAME : c_int = b'c' as c_int;
+
+const PG_DIAG_DATATYPE_NAME : c_int = b'd' as c_int;
+
+const PG_DIAG_CONSTRAINT_NAME : c_int = b'n' as c_int;
+
+/// An error with the optional fields of ereport, which clients receive as separate fields of
+/// the error message (so drivers can branch on the SQLSTATE, or on the violated constraint):
+///
+/// ```rust,ignore
+/// log::ErrorReport::new(&format!("account {} is overdrawn", id))
+///     .errcode(SqlState::CheckViolation)
+///     .errdetail(&format!("The balance would be {}.", balance))
+///     .errhint("Deposit funds before withdrawing.")
+///     .errtable("public", "accounts")
+///     .errconstraint("positive_balance")
+///     .raise()
+/// ```
+///
+/// Errors are reported at the location raise is called from, and have SQLSTATE XX000
+/// (InternalError) unless set by errcode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorReport {
+    code : SqlState,
+    message : String,
+    detail : Option<String>,
+    hint : Option<String>,
+    context : Option<String>,
+    position : Option<i32>,
+    schema_name : Option<String>,
+    table_name : Option<String>,
+    column_name : Option<String>,
+    datatype_name : Option<String>,
+    constraint_name : Option<String>
+}
+
+impl ErrorReport {
+
+    pub fn new(message : &str) -> Self {
+        ErrorReport {
+            code : SqlState::InternalError,
+            message : message.to_string(),
+            detail : None,
+            hint : None,
+            context : None,
+            position : None,
+            schema_name : None,
+            table_name : None,
+            column_name : None,
+            datatype_name : None,
+            constraint_name : None
+        }
+    }
+
+    pub fn errcode(mut self, code : SqlState) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Secondary message, with further details about the error.
+    pub fn errdetail(mut self, detail : &str) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    /// Suggestion about what to do about the error.
+    pub fn errhint(mut self, hint : &str) -> Self {
+        self.hint = Some(hint.to_string());
+        self
+    }
+
+    /// Context line (e.g. the item being processed when the error happened), which the server
+    /// reports before the context lines of the callers (such as the SQL statement that called the
+    /// function).
+    pub fn errcontext(mut self, context : &str) -> Self {
+        self.context = Some(context.to_string());
+        self
+    }
+
+    /// Position of the error at the query string of the client, as a 1-based character index.
+    pub fn errposition(mut self, position : i32) -> Self {
+        self.position = Some(position);
+        self
+    }
+
+    /// Table the error refers to.
+    pub fn errtable(mut self, schema : &str, table : &str) -> Self {
+        self.schema_name = Some(schema.to_string());
+        self.table_name = Some(table.to_string());
+        self
+    }
+
+    /// Column (of the table given to errtable) the error refers to.
+    pub fn errcolumn(mut self, column : &str) -> Self {
+        self.column_name = Some(column.to_string());
+        self
+    }
+
+    /// Data type the error refers to (e.g. a domain whose constraint was violated).
+    pub fn errdatatype(mut self, schema : &str, datatype : &str) -> Self {
+        self.schema_name = Some(schema.to_string());
+        self.datatype_name = Some(datatype.to_string());
+        self
+    }
+
+    /// Constraint (of the table given to errtable, or of the domain given to errdatatype) the error
+    /// refers to.
+    pub fn errconstraint(mut self, constraint : &str) -> Self {
+        self.constraint_name = Some(constraint.to_string());
+        self
+    }
+
+    /// Raises the error, which stops execution of the current function as Error::raise does.
+    #[track_caller]
+    pub fn raise(self) -> ! {
+        let location = Location::caller();
+        let c_string = |s : &str| CString::new(s.replace('\0', "")).unwrap();
+        let message = c_string(&self.message);
+        let detail = self.detail.as_deref().map(c_string);
+        let hint = self.hint.as_deref().map(c_string);
+        let context = self.context.as_deref().map(c_string);
+        let fields : Vec<(c_int, CString)> = [
+            (PG_DIAG_SCHEMA_NAME, &self.schema_name),
+            (PG_DIAG_TABLE_NAME, &self.table_name),
+            (PG_DIAG_COLUMN_NAME, &self.column_name),
+            (PG_DIAG_DATATYPE_NAME, &self.datatype_name),
+            (PG_DIAG_CONSTRAINT_NAME, &self.constraint_name)
+        ].iter()
+            .filter_map(|(field, value)| value.as_ref().map(|value| (*field, c_string(value)) ) )
+            .collect();
+        let code = self.code.packed();
+        let position = self.position;
+        drop(self);
+
+        // The strings are copied into the error data, so they are released before errfinish.
+        unsafe {
+            if ffi::errstart(ERROR, ptr::null()) {
+                ffi::errcode(code);
+                ffi::errmsg(b"%s\0".as_ptr() as *const c_char, message.as_ptr());
+                if let Some(detail) = &detail {
+                    ffi::errdetail(b"%s\0".as_ptr() as *const c_char, detail.as_ptr());
+                }
+                if let Some(hint) = &hint {
+                    ffi::errhint(b"%s\0".as_ptr() as *const c_char, hint.as_ptr());
+                }
+                if let Some(context) = &context {
+                    ffi::errcontext_msg(b"%s\0".as_ptr() as *const c_char, context.as_ptr());
+                }
+                if let Some(position) = position {
+                    ffi::errposition(position);
+                }
+                for (field, value) in fields.iter() {
+                    ffi::err_generic_string(*field, value.as_ptr());
+                }
+            }
+            drop((message, detail, hint, context, fields));
+            let file = error::source_file(location.file());
+            error::guard(|| ffi::errfinish(file, location.line() as c_int, ptr::null()) );
+        }
         unreachable!()
     }
+
 }
 
 /*impl Log {
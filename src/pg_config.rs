@@ -0,0 +1,2 @@
+// Constants read by build.rs from pg_config.h and pg_config_manual.h of the target server.
+include!(concat!(env!("OUT_DIR"), "/pg_config.rs"));
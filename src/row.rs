@@ -0,0 +1,466 @@
+use std::os::raw::{c_int, c_void};
+use std::ffi::{CStr, CString};
+use std::ptr;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use super::datum::{Datum, Oid, FromDatum, IntoDatum};
+use super::fmgr::{self, FunctionCallInfo};
+use super::vla;
+use super::{ffi, log, error};
+
+/// Prefix of TupleDescData (tupdesc.h), which describes the attributes of a row. Only the
+/// attribute count is read here; the attributes themselves are handled by the server.
+#[repr(C)]
+pub struct TupleDescData {
+    pub natts : c_int,
+    pub tdtypeid : Oid,
+    pub tdtypmod : i32,
+    pub tdrefcount : c_int
+}
+
+pub type TupleDesc = *mut TupleDescData;
+
+/// ABI-compatible with HeapTupleData (htup.h).
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct HeapTupleData {
+    t_len : u32,
+    t_self : [u16; 3],
+    t_table_oid : Oid,
+    pub(crate) t_data : *mut c_void
+}
+
+// TypeFuncClass (funcapi.h)
+const TYPEFUNC_COMPOSITE : c_int = 1;
+
+const TYPEFUNC_COMPOSITE_DOMAIN : c_int = 2;
+
+/// Types that can be returned as composite values (rows), either as the result of a function
+/// or as each item of a table function. Implemented for tuples (of up to 12 values convertible
+/// into datums) and for structs deriving PgRow or PgComposite. Functions returning PgRow structs
+/// declare their columns as OUT arguments (or as the columns of RETURNS TABLE), while PgComposite
+/// structs are declared as named composite types, which can also be taken as arguments, stored
+/// at tables and nested into other composites or arrays:
+///
+/// ```rust,ignore
+/// #[derive(PgRow)]
+/// struct Stats {
+///     count : i64,
+///     mean : Option<f64>
+/// }
+///
+/// #[pg_extern(immutable)]
+/// fn stats(a : Array<f64>) -> Stats {
+///     let values : Vec<f64> = a.iter().flatten().collect();
+///     let mean = if values.is_empty() { None } else { Some(values.iter().sum::<f64>() / values.len() as f64) };
+///     Stats { count : values.len() as i64, mean }
+/// }
+///
+/// #[derive(PgComposite)]
+/// struct Interval {
+///     low : f64,
+///     high : f64
+/// }
+///
+/// #[pg_extern(immutable)]
+/// fn widen(i : Interval, by : f64) -> Interval {
+///     Interval { low : i.low - by, high : i.high + by }
+/// }
+///
+/// #[pg_extern(immutable, columns(word, pos))]
+/// fn words(t : Text) -> impl Iterator<Item = (Text, i32)> {
+///     let words : Vec<_> = t.as_ref().split_whitespace().map(Text::from).collect();
+///     words.into_iter().zip(1..)
+/// }
+/// ```
+pub trait IntoRow {
+
+    /// Converts the value into the datums of its attributes, in declaration order. None
+    /// represents a NULL row.
+    fn into_row(self) -> Option<Vec<Option<Datum>>>;
+
+}
+
+impl<R : IntoRow> IntoRow for Option<R> {
+
+    fn into_row(self) -> Option<Vec<Option<Datum>>> {
+        self.and_then(|r| r.into_row() )
+    }
+
+}
+
+macro_rules! impl_tuple_row {
+    ($($ty:ident $ix:tt),+) => {
+        impl<$($ty : IntoDatum),+> IntoRow for ($($ty,)+) {
+
+            fn into_row(self) -> Option<Vec<Option<Datum>>> {
+                Some(vec![$(self.$ix.into_datum()),+])
+            }
+
+        }
+    };
+}
+
+impl_tuple_row!(A 0, B 1);
+impl_tuple_row!(A 0, B 1, C 2);
+impl_tuple_row!(A 0, B 1, C 2, D 3);
+impl_tuple_row!(A 0, B 1, C 2, D 3, E 4);
+impl_tuple_row!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_tuple_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_tuple_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+impl_tuple_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+impl_tuple_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+impl_tuple_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+impl_tuple_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+
+/// Types that can be read from the attributes of a row, in order (the counterpart of IntoRow):
+/// Values implementing FromDatum are read from the first attribute, and tuples of them from
+/// the first attributes, one for each element. Used to read the rows returned by SPI queries.
+pub trait FromRow : Sized {
+
+    fn from_row(row : &Row) -> Self;
+
+}
+
+impl<T : FromDatum> FromRow for T {
+
+    fn from_row(row : &Row) -> Self {
+        row.get_at(0)
+    }
+
+}
+
+macro_rules! impl_tuple_from_row {
+    ($($ty:ident $ix:tt),+) => {
+        impl<$($ty : FromDatum),+> FromRow for ($($ty,)+) {
+
+            fn from_row(row : &Row) -> Self {
+                ($(row.get_at::<$ty>($ix),)+)
+            }
+
+        }
+    };
+}
+
+impl_tuple_from_row!(A 0, B 1);
+impl_tuple_from_row!(A 0, B 1, C 2);
+impl_tuple_from_row!(A 0, B 1, C 2, D 3);
+impl_tuple_from_row!(A 0, B 1, C 2, D 3, E 4);
+impl_tuple_from_row!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_tuple_from_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_tuple_from_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+impl_tuple_from_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+impl_tuple_from_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+impl_tuple_from_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+impl_tuple_from_row!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+
+/// Resolves the row type the caller expects from the function (its declared composite type,
+/// the OUT arguments or the columns of RETURNS TABLE), via get_call_result_type. The descriptor
+/// is allocated in the current memory context and blessed, so rows formed with it can be
+/// returned as composite datums.
+pub unsafe fn result_desc(fcinfo : FunctionCallInfo) -> TupleDesc {
+    let mut type_oid = Oid::default();
+    let mut desc : TupleDesc = ptr::null_mut();
+    match ffi::get_call_result_type(fcinfo, &mut type_oid, &mut desc) {
+        TYPEFUNC_COMPOSITE | TYPEFUNC_COMPOSITE_DOMAIN => ffi::BlessTupleDesc(desc),
+        _ => log::Error::raise("Function returning record called in context that cannot accept type record")
+    }
+}
+
+/// Forms a composite datum (via heap_form_tuple) from the attributes of the row, which must
+/// match the descriptor in number and types. Returns None for NULL rows.
+pub unsafe fn form_row<R : IntoRow>(desc : TupleDesc, row : R) -> Option<Datum> {
+    let attrs = row.into_row()?;
+    if attrs.len() != (*desc).natts as usize {
+        log::Error::raise(&format!(
+            "Returned row has {} attribute(s), but the function result has {}",
+            attrs.len(),
+            (*desc).natts
+        ));
+    }
+    let mut nulls : Vec<bool> = attrs.iter().map(|a| a.is_none() ).collect();
+    let mut values : Vec<Datum> = attrs.into_iter().map(|a| a.unwrap_or_default() ).collect();
+    let tuple = ffi::heap_form_tuple(desc, values.as_mut_ptr(), nulls.as_mut_ptr());
+    Some(ffi::HeapTupleHeaderGetDatum((*tuple).t_data))
+}
+
+thread_local! {
+    // Composite types resolved by composite_type_oid, by the function they were resolved for.
+    static COMPOSITE_TYPES : RefCell<HashMap<(Oid, String), Oid>> = RefCell::new(HashMap::new());
+}
+
+// Name of the composite type (which is also the name of its relation), if typid is one.
+unsafe fn composite_name(typid : Oid) -> Option<String> {
+    let relid = ffi::get_typ_typrelid(typid);
+    if relid == Oid::default() {
+        return None;
+    }
+    let name = ffi::get_rel_name(relid);
+    if name.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+    }
+}
+
+unsafe fn resolve_composite(fcinfo : FunctionCallInfo, name : &str) -> Oid {
+    let mut result_type = Oid::default();
+    let mut desc : TupleDesc = ptr::null_mut();
+    ffi::get_call_result_type(fcinfo, &mut result_type, &mut desc);
+    for type_oid in [result_type, ffi::get_element_type(result_type)].iter() {
+        if composite_name(*type_oid).as_deref() == Some(name) {
+            return *type_oid;
+        }
+    }
+    let schema = ffi::get_namespace_name(ffi::get_func_namespace((*(*fcinfo).flinfo).fn_oid));
+    if schema.is_null() {
+        log::Error::raise(&format!("Composite type \"{}\" not found", name));
+    }
+    let c_name = CString::new(name).unwrap();
+    let qualified = ffi::quote_qualified_identifier(schema, c_name.as_ptr());
+    let datum = ffi::DirectFunctionCall1Coll(ffi::regtypein, Oid::default(), Datum::from_ptr(qualified));
+    let type_oid = Oid::from_datum(datum, false).unwrap();
+    if !ffi::type_is_rowtype(type_oid) {
+        log::Error::raise(&format!("Type \"{}\" is not a composite type", name));
+    }
+    type_oid
+}
+
+/// Oid of the composite type with the given name, declared by the extension. The type is taken
+/// from the declared result of the function being called (via get_call_result_type), if it is
+/// the composite type or an array of it. Otherwise (e.g. for composites nested into the result),
+/// it is looked up at the schema of the function, where the extension script declared both. The
+/// search path is never used, so the type resolves the same wherever the extension is installed.
+/// Raises an error outside of a function call, or if there is no such type.
+pub fn composite_type_oid(name : &str) -> Oid {
+    let fcinfo = match fmgr::current_call() {
+        Some(fcinfo) => fcinfo,
+        None => log::Error::raise(&format!("Composite type \"{}\" can only be resolved while a function is called", name))
+    };
+    let key = (unsafe { (*(*fcinfo).flinfo).fn_oid }, name.to_string());
+    if let Some(type_oid) = COMPOSITE_TYPES.with(|types| types.borrow().get(&key).copied() ) {
+        return type_oid;
+    }
+    let type_oid = unsafe { resolve_composite(fcinfo, name) };
+    COMPOSITE_TYPES.with(|types| types.borrow_mut().insert(key, type_oid) );
+    type_oid
+}
+
+/// Forms a composite datum of the given (non-anonymous) row type from the attributes of the
+/// row. Returns None for NULL rows.
+pub unsafe fn form_composite<R : IntoRow>(type_oid : Oid, row : R) -> Option<Datum> {
+    let desc = ffi::lookup_rowtype_tupdesc(type_oid, -1);
+    let datum = form_row(desc, row);
+    ffi::DecrTupleDescRefCount(desc);
+    datum
+}
+
+/// Converts a returned row into the result datum of a function returning a composite type,
+/// flagging the result as SQL NULL if the row converts to None.
+pub unsafe fn ret_row<R : IntoRow>(fcinfo : FunctionCallInfo, row : R) -> Datum {
+    match form_row(result_desc(fcinfo), row) {
+        Some(datum) => datum,
+        None => {
+            (*fcinfo).isnull = true;
+            Datum::default()
+        }
+    }
+}
+
+/// Prefix of HeapTupleHeaderData (htup_details.h) for tuples stored as composite datums, whose
+/// header records the row type instead of transaction information.
+#[repr(C)]
+struct DatumTupleHeader {
+    datum_len_ : i32,
+    datum_typmod : i32,
+    datum_typeid : Oid
+}
+
+/// A composite value (a table row, or any value of a composite type) received as an argument.
+/// Arguments of this type are declared as record, so any row can be passed to the function.
+/// Attributes are read by name or by position (counted from zero), and are converted with the
+/// same FromDatum implementations as function arguments:
+///
+/// ```rust,ignore
+/// #[pg_extern(stable)]
+/// fn describe(r : Row) -> Text {
+///     let name : Text = r.get("name");
+///     let age : Option<i32> = r.get("age");
+///     Text::from(&format!("{} ({})", name, age.map(|a| a.to_string() ).unwrap_or_default()))
+/// }
+/// ```
+///
+/// Reading an attribute that does not exist, whose type is not compatible with the requested
+/// type, or that is NULL when the requested type is not an Option raises an error. Attributes
+/// are replaced with set and set_at, which make the row refer to a modified copy (the rows of
+/// trigger functions are modified this way before being returned to the server).
+pub struct Row {
+    tuple : HeapTupleData,
+    desc : TupleDesc,
+
+    // Whether the descriptor was acquired from the type cache (and must be released).
+    cached_desc : bool
+}
+
+impl Row {
+
+    /// Row referring to a tuple described by desc, which must outlive the row (such as the
+    /// descriptor of the relation a trigger fired for).
+    pub(crate) unsafe fn from_tuple(tuple : *const HeapTupleData, desc : TupleDesc) -> Self {
+        Row { tuple : *tuple, desc, cached_desc : false }
+    }
+
+    pub(crate) fn tuple(&self) -> &HeapTupleData {
+        &self.tuple
+    }
+
+    pub(crate) fn desc(&self) -> TupleDesc {
+        self.desc
+    }
+
+    /// Oid of the row type (the table or composite type the row belongs to).
+    pub fn type_oid(&self) -> Oid {
+        unsafe { (*self.desc).tdtypeid }
+    }
+
+    /// Number of attributes (including dropped columns, which always read as NULL).
+    pub fn len(&self) -> usize {
+        unsafe { (*self.desc).natts as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Name of the attribute at the given position.
+    pub fn name(&self, pos : usize) -> Option<String> {
+        if pos >= self.len() {
+            return None;
+        }
+        unsafe {
+            let name = ffi::SPI_fname(self.desc, pos as c_int + 1);
+            if name.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    /// Position of the attribute with the given name.
+    pub fn position(&self, name : &str) -> Option<usize> {
+        let c_name = CString::new(name).ok()?;
+        match unsafe { ffi::SPI_fnumber(self.desc, c_name.as_ptr()) } {
+            attnum if attnum > 0 => Some(attnum as usize - 1),
+
+            // System columns have negative numbers, and SPI_ERROR_NOATTRIBUTE is also negative.
+            _ => None
+        }
+    }
+
+    /// Reads the attribute with the given name.
+    pub fn get<T : FromDatum>(&self, name : &str) -> T {
+        match self.position(name) {
+            Some(pos) => self.read(pos, name),
+            None => log::Error::raise(&format!("Row has no attribute named \"{}\"", name))
+        }
+    }
+
+    /// Reads the attribute at the given position.
+    pub fn get_at<T : FromDatum>(&self, pos : usize) -> T {
+        if pos >= self.len() {
+            log::Error::raise(&format!("Row has {} attribute(s), but attribute {} was requested", self.len(), pos));
+        }
+        self.read(pos, &self.name(pos).unwrap_or_default())
+    }
+
+    fn read<T : FromDatum>(&self, pos : usize, name : &str) -> T {
+        unsafe {
+            let attnum = pos as c_int + 1;
+            let type_oid = ffi::SPI_gettypeid(self.desc, attnum);
+            if !T::is_compatible(type_oid) {
+                log::Error::raise(&format!("Attribute \"{}\" has an incompatible type (oid {})", name, type_oid.0));
+            }
+            let mut is_null = false;
+            let datum = ffi::SPI_getbinval(&self.tuple as *const _ as *mut _, self.desc, attnum, &mut is_null);
+            match T::from_datum(datum, is_null) {
+                Some(value) => value,
+                None => log::Error::raise(&format!("Attribute \"{}\" must not be null", name))
+            }
+        }
+    }
+
+    /// Replaces the attribute with the given name.
+    pub fn set<T : IntoDatum>(&mut self, name : &str, value : T) {
+        match self.position(name) {
+            Some(pos) => self.write(pos, name, value),
+            None => log::Error::raise(&format!("Row has no attribute named \"{}\"", name))
+        }
+    }
+
+    /// Replaces the attribute at the given position.
+    pub fn set_at<T : IntoDatum>(&mut self, pos : usize, value : T) {
+        if pos >= self.len() {
+            log::Error::raise(&format!("Row has {} attribute(s), but attribute {} was requested", self.len(), pos));
+        }
+        self.write(pos, &self.name(pos).unwrap_or_default(), value)
+    }
+
+    // The value must be binary-coercible to the attribute type (so text can be written to
+    // varchar attributes, for instance). The modified tuple is allocated in the current memory
+    // context, and the original tuple is left unchanged.
+    fn write<T : IntoDatum>(&mut self, pos : usize, name : &str, value : T) {
+        unsafe {
+            let mut attnum = pos as c_int + 1;
+            let type_oid = ffi::SPI_gettypeid(self.desc, attnum);
+            if type_oid == Oid::default() || !ffi::IsBinaryCoercible(T::type_oid(), type_oid) {
+                log::Error::raise(&format!("Attribute \"{}\" has an incompatible type (oid {})", name, type_oid.0));
+            }
+            let datum = value.into_datum();
+            let mut is_null = datum.is_none();
+            let mut datum = datum.unwrap_or_default();
+            let tuple = ffi::heap_modify_tuple_by_cols(&mut self.tuple, self.desc, 1, &mut attnum, &mut datum, &mut is_null);
+            self.tuple = *tuple;
+        }
+    }
+
+}
+
+impl FromDatum for Row {
+
+    fn is_compatible(type_oid : Oid) -> bool {
+        unsafe { ffi::type_is_rowtype(type_oid) }
+    }
+
+    unsafe fn from_datum(datum : Datum, is_null : bool) -> Option<Self> {
+        if is_null {
+            return None;
+        }
+        // Same as the HeapTupleData built by GetAttributeByNum for composite datums.
+        let header = ffi::pg_detoast_datum(datum.as_ptr());
+        let tuple = HeapTupleData {
+            t_len : vla::varsize_any(header) as u32,
+            t_self : [0; 3],
+            t_table_oid : Oid::default(),
+            t_data : header as *mut c_void
+        };
+        let typed = &*(header as *const DatumTupleHeader);
+        let desc = ffi::lookup_rowtype_tupdesc(typed.datum_typeid, typed.datum_typmod);
+        Some(Row { tuple, desc, cached_desc : true })
+    }
+
+}
+
+impl Drop for Row {
+
+    // Equivalent to ReleaseTupleDesc: Only reference-counted descriptors (the ones cached
+    // by the type cache) are released.
+    fn drop(&mut self) {
+        unsafe {
+            if self.cached_desc && (*self.desc).tdrefcount >= 0 && !error::unwinding_error() {
+                ffi::DecrTupleDescRefCount(self.desc);
+            }
+        }
+    }
+
+}
@@ -0,0 +1,525 @@
+use std::os::raw::{c_char, c_int};
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::ptr;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use super::datum::{Datum, Oid, IntoDatum};
+use super::row::{Row, FromRow, TupleDesc, HeapTupleData};
+use super::ffi::{self, MemoryContext};
+use super::error;
+
+/// Prefix of SPITupleTable (spi.h), holding the rows returned by the last query.
+#[repr(C)]
+pub(crate) struct SPITupleTable {
+    tupdesc : TupleDesc,
+    vals : *mut *mut HeapTupleData,
+    numvals : u64
+}
+
+// SPI_OK_CONNECT (spi.h)
+const SPI_OK_CONNECT : c_int = 1;
+
+/// Error code returned by an SPI function (one of the negative SPI_ERROR_* codes of spi.h).
+/// Errors raised by the server while running a query (syntax errors, constraint violations,
+/// etc.) are not returned as SpiError: they abort the function as any other server error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpiError {
+    pub code : i32
+}
+
+impl fmt::Display for SpiError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = unsafe { CStr::from_ptr(ffi::SPI_result_code_string(self.code)) };
+        write!(f, "{}", name.to_string_lossy())
+    }
+}
+
+impl std::error::Error for SpiError { }
+
+/// A query parameter ($1, $2, etc.), converted from any value implementing IntoDatum:
+///
+/// ```rust,ignore
+/// spi.execute("select name from users where id = $1 and active = $2", &[id.into(), true.into()])?;
+/// ```
+pub struct Arg {
+    type_oid : Oid,
+    datum : Option<Datum>
+}
+
+impl<T : IntoDatum> From<T> for Arg {
+    fn from(value : T) -> Self {
+        Arg { type_oid : T::type_oid(), datum : value.into_datum() }
+    }
+}
+
+/// Rows returned by a query (or the number of rows affected by a command without RETURNING).
+/// The rows are copied out of the SPI memory, so they (and the values read from them) remain
+/// valid after the connection is finished, until the memory context of the calling function
+/// is reset.
+pub struct SpiResult {
+    processed : u64,
+    rows : Vec<Row>
+}
+
+impl SpiResult {
+
+    /// Number of rows returned by the query, or affected by the command.
+    pub fn processed(&self) -> u64 {
+        self.processed
+    }
+
+    /// Rows returned by the query (empty for commands without RETURNING).
+    pub fn rows(&self) -> &[Row] {
+        &self.rows
+    }
+
+    /// Reads each returned row as a value (for single-column queries) or a tuple.
+    pub fn typed<T : FromRow>(&self) -> Vec<T> {
+        self.rows.iter().map(T::from_row).collect()
+    }
+
+    /// Reads the first returned row (if any) as a value or a tuple.
+    pub fn first<T : FromRow>(&self) -> Option<T> {
+        self.rows.first().map(T::from_row)
+    }
+
+}
+
+/// Connection to the Server Programming Interface, which runs SQL from within a function. The
+/// connection is opened by connect (or connect_read_only) and finished when the value is dropped:
+///
+/// ```rust,ignore
+/// #[pg_extern(stable)]
+/// fn country_name(code : Text) -> Option<Text> {
+///     let spi = Spi::connect_read_only().unwrap();
+///     let result = spi.execute("select name from countries where code = $1", &[code.into()]).unwrap();
+///     result.first()
+/// }
+/// ```
+///
+/// Read-only connections run queries with the snapshot of the calling statement, as stable and
+/// immutable functions must (and reject commands that modify data). Read-write connections see
+/// the changes made by previous commands of the same statement.
+pub struct Spi {
+    read_only : bool,
+
+    // Context of the caller, into which results are copied.
+    outer : MemoryContext
+}
+
+impl Spi {
+
+    /// Opens a read-write connection.
+    pub fn connect() -> Result<Spi, SpiError> {
+        Self::open(false)
+    }
+
+    /// Opens a read-only connection.
+    pub fn connect_read_only() -> Result<Spi, SpiError> {
+        Self::open(true)
+    }
+
+    fn open(read_only : bool) -> Result<Spi, SpiError> {
+        unsafe {
+            let outer = ffi::CurrentMemoryContext;
+            match ffi::SPI_connect() {
+                SPI_OK_CONNECT => {
+                    let spi = Spi { read_only, outer };
+                    spi.restore_context();
+                    Ok(spi)
+                },
+                code => Err(SpiError { code })
+            }
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Runs a query (or command), binding args to its parameters $1, $2, etc.
+    pub fn execute(&self, query : &str, args : &[Arg]) -> Result<SpiResult, SpiError> {
+        let c_query = query_string(query)?;
+        let (mut types, mut values, nulls) = bind(args);
+        unsafe {
+            let code = ffi::SPI_execute_with_args(
+                c_query.as_ptr(),
+                args.len() as c_int,
+                types.as_mut_ptr(),
+                values.as_mut_ptr(),
+                nulls.as_ptr(),
+                self.read_only,
+                0
+            );
+            self.restore_context();
+            if code < 0 {
+                return Err(SpiError { code });
+            }
+            Ok(SpiResult { processed : ptr::addr_of!(ffi::SPI_processed).read(), rows : self.take_rows() })
+        }
+    }
+
+    /// Prepares a query whose parameters $1, $2, etc. have the given types. The plan lives until
+    /// the connection is finished, unless kept with Plan::keep.
+    pub fn prepare(&self, query : &str, types : &[Oid]) -> Result<Plan<'_>, SpiError> {
+        let c_query = query_string(query)?;
+        let mut types = types.to_vec();
+        unsafe {
+            let ptr = ffi::SPI_prepare(c_query.as_ptr(), types.len() as c_int, types.as_mut_ptr());
+            self.restore_context();
+            if ptr.is_null() {
+                return Err(SpiError { code : ptr::addr_of!(ffi::SPI_result).read() });
+            }
+            Ok(Plan { ptr, kept : false, _spi : PhantomData })
+        }
+    }
+
+    /// Returns the plan for the query prepared by an earlier call at this backend, or prepares
+    /// (and keeps) it if this is the first call. Plans are cached by the query text and parameter
+    /// types, and live until the backend exits.
+    pub fn prepare_cached(&self, query : &str, types : &[Oid]) -> Result<&'static Plan<'static>, SpiError> {
+        let key = (query.to_string(), types.to_vec());
+        if let Some(plan) = PLANS.with(|plans| plans.borrow().get(&key).copied() ) {
+            return Ok(plan);
+        }
+        let plan : &'static Plan<'static> = Box::leak(Box::new(self.prepare(query, types)?.keep()?));
+        PLANS.with(|plans| plans.borrow_mut().insert(key, plan) );
+        Ok(plan)
+    }
+
+    /// Runs a prepared plan, binding args to its parameters. Each argument must have the type
+    /// (or be binary-coercible to the type) the parameter was prepared with.
+    pub fn execute_plan(&self, plan : &Plan<'_>, args : &[Arg]) -> Result<SpiResult, SpiError> {
+        let (mut values, nulls) = plan.bind(args)?;
+        unsafe {
+            let code = ffi::SPI_execute_plan(plan.ptr, values.as_mut_ptr(), nulls.as_ptr(), self.read_only, 0);
+            self.restore_context();
+            if code < 0 {
+                return Err(SpiError { code });
+            }
+            Ok(SpiResult { processed : ptr::addr_of!(ffi::SPI_processed).read(), rows : self.take_rows() })
+        }
+    }
+
+    /// Opens a cursor over the rows returned by a query, binding args to its parameters.
+    pub fn cursor(&self, query : &str, args : &[Arg]) -> Result<Cursor<'_>, SpiError> {
+        let c_query = query_string(query)?;
+        let (mut types, mut values, nulls) = bind(args);
+        unsafe {
+            let portal = ffi::SPI_cursor_open_with_args(
+                ptr::null(),
+                c_query.as_ptr(),
+                args.len() as c_int,
+                types.as_mut_ptr(),
+                values.as_mut_ptr(),
+                nulls.as_ptr(),
+                self.read_only,
+                0
+            );
+            self.restore_context();
+            Ok(Cursor::new(self, portal))
+        }
+    }
+
+    /// Opens a cursor over the rows returned by a prepared plan, binding args to its parameters.
+    pub fn cursor_plan(&self, plan : &Plan<'_>, args : &[Arg]) -> Result<Cursor<'_>, SpiError> {
+        let (mut values, nulls) = plan.bind(args)?;
+        unsafe {
+            let portal = ffi::SPI_cursor_open(ptr::null(), plan.ptr, values.as_mut_ptr(), nulls.as_ptr(), self.read_only);
+            self.restore_context();
+            Ok(Cursor::new(self, portal))
+        }
+    }
+
+    // SPI functions leave the SPI procedure context as the current one, which is deleted when the
+    // connection is finished. Switching back to the context of the caller after each call keeps
+    // whatever the function allocates while connected (detoasted values, etc.) valid after that.
+    unsafe fn restore_context(&self) {
+        ffi::CurrentMemoryContext = self.outer;
+    }
+
+    // Copies the rows of the last result into the outer memory context, and releases them
+    // from the SPI memory.
+    unsafe fn take_rows(&self) -> Vec<Row> {
+        let table = ptr::addr_of!(ffi::SPI_tuptable).read();
+        if table.is_null() {
+            return Vec::new();
+        }
+        let desc = ffi::CreateTupleDescCopy((*table).tupdesc);
+        let rows = (0..(*table).numvals as usize)
+            .map(|i| Row::from_tuple(ffi::heap_copytuple(*(*table).vals.add(i)), desc) )
+            .collect();
+        ffi::SPI_freetuptable(table);
+        rows
+    }
+
+}
+
+impl Drop for Spi {
+
+    fn drop(&mut self) {
+        if error::unwinding_error() {
+            return;
+        }
+        unsafe {
+            ffi::SPI_finish();
+        }
+    }
+
+}
+
+thread_local! {
+    // Plans kept by Spi::prepare_cached.
+    static PLANS : RefCell<HashMap<(String, Vec<Oid>), &'static Plan<'static>>> = RefCell::new(HashMap::new());
+}
+
+fn query_string(query : &str) -> Result<CString, SpiError> {
+    CString::new(query).map_err(|_| SpiError { code : ffi::SPI_ERROR_ARGUMENT })
+}
+
+// Splits the arguments into the types, values and null flags SPI functions take.
+fn bind(args : &[Arg]) -> (Vec<Oid>, Vec<Datum>, Vec<c_char>) {
+    let types = args.iter().map(|arg| arg.type_oid ).collect();
+    let values = args.iter().map(|arg| arg.datum.unwrap_or_default() ).collect();
+    let nulls = args.iter()
+        .map(|arg| if arg.datum.is_some() { b' ' as c_char } else { b'n' as c_char })
+        .collect();
+    (types, values, nulls)
+}
+
+/// A prepared query (SPI_prepare), which is parsed and planned once and might be run many times.
+/// A plan borrows the connection it was prepared with, unless it is kept: kept plans live until
+/// dropped, so they can be stored (e.g. at a thread_local) and reused by later calls of the
+/// function at the same backend. Spi::prepare_cached does that for you:
+///
+/// ```rust,ignore
+/// let spi = Spi::connect_read_only().unwrap();
+/// let plan = spi.prepare_cached("select name from users where id = $1", &[i32::type_oid()]).unwrap();
+/// let name : Option<Text> = spi.execute_plan(plan, &[id.into()]).unwrap().first();
+/// ```
+///
+/// The server re-plans the query when the objects it refers to change.
+pub struct Plan<'a> {
+    ptr : ffi::SPIPlanPtr,
+    kept : bool,
+    _spi : PhantomData<&'a Spi>
+}
+
+impl<'a> Plan<'a> {
+
+    /// Moves the plan out of the connection memory (SPI_keepplan). Plans that are already kept
+    /// are returned as they are.
+    pub fn keep(self) -> Result<Plan<'static>, SpiError> {
+        // The plan is moved into the returned one, so it must not be freed when self goes away.
+        let plan = ManuallyDrop::new(self);
+        if !plan.kept {
+            match unsafe { ffi::SPI_keepplan(plan.ptr) } {
+                0 => { },
+                code => return Err(SpiError { code })
+            }
+        }
+        Ok(Plan { ptr : plan.ptr, kept : true, _spi : PhantomData })
+    }
+
+    /// Types of the parameters the plan was prepared with.
+    pub fn arg_types(&self) -> Vec<Oid> {
+        unsafe {
+            (0..ffi::SPI_getargcount(self.ptr)).map(|i| ffi::SPI_getargtypeid(self.ptr, i) ).collect()
+        }
+    }
+
+    // Checks the arguments against the parameters of the plan, since SPI would read the datums
+    // as the parameter types.
+    fn bind(&self, args : &[Arg]) -> Result<(Vec<Datum>, Vec<c_char>), SpiError> {
+        let param_types = self.arg_types();
+        let (types, values, nulls) = bind(args);
+        let matches = types.len() == param_types.len() &&
+            types.iter().zip(param_types.iter()).all(|(arg, param)| unsafe { ffi::IsBinaryCoercible(*arg, *param) } );
+        if !matches {
+            return Err(SpiError { code : ffi::SPI_ERROR_PARAM });
+        }
+        Ok((values, nulls))
+    }
+
+}
+
+impl<'a> Drop for Plan<'a> {
+
+    fn drop(&mut self) {
+        // Plans that are not kept are released when the connection is finished.
+        if self.kept && !error::unwinding_error() {
+            unsafe {
+                ffi::SPI_freeplan(self.ptr);
+            }
+        }
+    }
+
+}
+
+// Rows fetched by each call to SPI_cursor_fetch, unless set by Cursor::batch_size.
+const DEFAULT_BATCH_SIZE : i64 = 1000;
+
+/// An open cursor over the rows of a query, which fetches a batch of rows at a time
+/// (SPI_cursor_fetch), so large results are scanned with bounded memory:
+///
+/// ```rust,ignore
+/// let spi = Spi::connect_read_only().unwrap();
+/// let mut cursor = spi.cursor("select amount from payments", &[]).unwrap().batch_size(10_000);
+/// let mut total = 0;
+/// while let Some(row) = cursor.next_row() {
+///     total += row.get::<Option<i64>>("amount").unwrap_or(0);
+/// }
+/// ```
+///
+/// The memory of each batch is released when the next one is fetched, so rows are borrowed from
+/// the cursor rather than returned by value (which is why Cursor is not an Iterator). The values
+/// read from a row without copying, such as Text, are only valid until the next batch is fetched
+/// as well: convert the ones that must be kept for longer into owned Rust values (or collect the
+/// rows with Spi::execute instead).
+pub struct Cursor<'a> {
+    portal : ffi::Portal,
+    batch_size : i64,
+
+    // Rows of the current batch, the index of the next row to return, and the last row returned.
+    table : *mut SPITupleTable,
+    next : usize,
+    row : Option<Row>,
+
+    // Whether the last fetch returned less rows than requested.
+    done : bool,
+    spi : &'a Spi
+}
+
+impl<'a> Cursor<'a> {
+
+    fn new(spi : &'a Spi, portal : ffi::Portal) -> Self {
+        Cursor { portal, batch_size : DEFAULT_BATCH_SIZE, table : ptr::null_mut(), next : 0, row : None, done : false, spi }
+    }
+
+    /// Sets how many rows are fetched at a time (at least one).
+    pub fn batch_size(mut self, n : usize) -> Self {
+        self.batch_size = n.clamp(1, i64::MAX as usize) as i64;
+        self
+    }
+
+    unsafe fn release_batch(&mut self) {
+        self.row = None;
+        if !self.table.is_null() {
+            ffi::SPI_freetuptable(self.table);
+            self.table = ptr::null_mut();
+        }
+    }
+
+    unsafe fn fetch(&mut self) {
+        self.release_batch();
+        ffi::SPI_cursor_fetch(self.portal, true, self.batch_size as _);
+        self.spi.restore_context();
+        self.table = ptr::addr_of!(ffi::SPI_tuptable).read();
+        self.next = 0;
+        self.done = (ptr::addr_of!(ffi::SPI_processed).read() as i64) < self.batch_size;
+    }
+
+    /// Returns the next row, fetching the next batch if the current one is exhausted, or None
+    /// once all rows were returned.
+    pub fn next_row(&mut self) -> Option<&Row> {
+        unsafe {
+            if self.table.is_null() || self.next as u64 >= (*self.table).numvals {
+                if self.done {
+                    self.release_batch();
+                    return None;
+                }
+                self.fetch();
+                if self.table.is_null() || (*self.table).numvals == 0 {
+                    return None;
+                }
+            }
+            let row = Row::from_tuple(*(*self.table).vals.add(self.next), (*self.table).tupdesc);
+            self.next += 1;
+            self.row = Some(row);
+            self.row.as_ref()
+        }
+    }
+
+}
+
+impl<'a> Drop for Cursor<'a> {
+
+    fn drop(&mut self) {
+        if error::unwinding_error() {
+            return;
+        }
+        unsafe {
+            self.release_batch();
+            ffi::SPI_cursor_close(self.portal);
+        }
+    }
+
+}
+
+// Stand-ins for the server functions reached by Plan::keep and Plan::drop (and by error::guard),
+// so the tests link without the server. They count the plans kept and freed. Tests reaching
+// error::guard hold SERVER, since the server globals are not thread-local.
+#[cfg(test)]
+#[allow(non_upper_case_globals, non_snake_case)]
+pub(crate) mod server {
+    use std::os::raw::{c_int, c_void};
+    use std::ptr;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    pub static SERVER : Mutex<()> = Mutex::new(());
+
+    pub static KEPT : AtomicUsize = AtomicUsize::new(0);
+
+    pub static FREED : AtomicUsize = AtomicUsize::new(0);
+
+    #[no_mangle]
+    static mut PG_exception_stack : *mut c_void = ptr::null_mut();
+
+    #[no_mangle]
+    static mut error_context_stack : *mut c_void = ptr::null_mut();
+
+    #[no_mangle]
+    static mut CurrentMemoryContext : *mut c_void = ptr::null_mut();
+
+    #[no_mangle]
+    static mut TopTransactionContext : *mut c_void = ptr::null_mut();
+
+    #[no_mangle]
+    extern "C" fn CopyErrorData() -> *mut c_void {
+        ptr::NonNull::dangling().as_ptr()
+    }
+
+    #[no_mangle]
+    extern "C" fn FlushErrorState() { }
+
+    #[no_mangle]
+    extern "C" fn FreeErrorData(_edata : *mut c_void) { }
+
+    #[no_mangle]
+    extern "C" fn SPI_keepplan(_plan : *mut c_void) -> c_int {
+        KEPT.fetch_add(1, Ordering::SeqCst);
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn SPI_freeplan(_plan : *mut c_void) -> c_int {
+        FREED.fetch_add(1, Ordering::SeqCst);
+        0
+    }
+}
+
+#[test]
+fn keep_twice() {
+    use std::sync::atomic::Ordering;
+    let _server = server::SERVER.lock().unwrap();
+    let plan : Plan<'static> = Plan { ptr : ptr::NonNull::dangling().as_ptr(), kept : false, _spi : PhantomData };
+    let plan = plan.keep().unwrap().keep().unwrap();
+    assert_eq!(server::KEPT.load(Ordering::SeqCst), 1);
+    assert_eq!(server::FREED.load(Ordering::SeqCst), 0);
+    drop(plan);
+    assert_eq!(server::FREED.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,145 @@
+use std::os::raw::{c_int, c_void};
+use std::{mem, ptr};
+use super::datum::{Datum, IntoDatum};
+use super::fmgr::FunctionCallInfo;
+use super::row::{self, IntoRow, TupleDesc};
+use super::ffi::{self, MemoryContext, MemoryContextCallback};
+use super::{log, error};
+
+/// ABI-compatible with FuncCallContext (funcapi.h), which holds the state of a set-returning
+/// function across the calls that produce its rows.
+#[repr(C)]
+pub struct FuncCallContext {
+    pub call_cntr : u64,
+    pub max_calls : u64,
+    pub user_fctx : *mut c_void,
+    pub attinmeta : *mut c_void,
+    pub multi_call_memory_ctx : MemoryContext,
+    pub tuple_desc : TupleDesc
+}
+
+// SetFunctionReturnMode flags (execnodes.h)
+const SFRM_VALUE_PER_CALL : c_int = 0x01;
+
+// ExprDoneCode (execnodes.h)
+const EXPR_MULTIPLE_RESULT : c_int = 1;
+
+const EXPR_END_RESULT : c_int = 2;
+
+/// ABI-compatible with ReturnSetInfo (execnodes.h), which the server passes at
+/// fcinfo->resultinfo when calling a set-returning function.
+#[repr(C)]
+pub struct ReturnSetInfo {
+    pub type_ : c_int,
+    pub econtext : *mut c_void,
+    pub expected_desc : *mut c_void,
+    pub allowed_modes : c_int,
+    pub return_mode : c_int,
+    pub is_done : c_int,
+    pub set_result : *mut c_void,
+    pub set_desc : *mut c_void
+}
+
+// Called when the multi-call memory context is deleted: Either after the last row, or when
+// the query stops consuming rows early (LIMIT, errors, etc.).
+unsafe extern "C" fn drop_iterator<I>(arg : *mut c_void) {
+    error::boundary(|| drop(Box::from_raw(arg as *mut I)) );
+}
+
+/// Produces the next row of a set-returning function in value-per-call mode (the equivalent of
+/// the SRF_FIRSTCALL_INIT, SRF_RETURN_NEXT and SRF_RETURN_DONE macros). At the first call, init is
+/// called with the multi-call memory context as the current context, so the arguments it reads
+/// (and anything palloc'd while building the iterator) remain valid until the set is exhausted.
+/// The iterator is kept at the FuncCallContext, and is dropped when the server releases that
+/// context. This is the entry point #[pg_extern] generates for functions returning
+/// impl Iterator<Item = T>:
+///
+/// ```rust,ignore
+/// #[pg_extern(immutable)]
+/// fn multiples(n : i64, count : i64) -> impl Iterator<Item = i64> {
+///     (1..=count).map(move |i| i * n )
+/// }
+/// ```
+pub unsafe fn next_value<I, F>(fcinfo : FunctionCallInfo, init : F) -> Datum
+where
+    F : FnOnce() -> I,
+    I : Iterator,
+    I::Item : IntoDatum
+{
+    next_with(fcinfo, false, init, |_, item| item.into_datum() )
+}
+
+/// Same as next_value, for table functions (functions whose items are rows). The row
+/// descriptor is resolved once, at the first call, and kept at the FuncCallContext.
+pub unsafe fn next_row<I, F>(fcinfo : FunctionCallInfo, init : F) -> Datum
+where
+    F : FnOnce() -> I,
+    I : Iterator,
+    I::Item : IntoRow
+{
+    next_with(fcinfo, true, init, |funcctx, item| row::form_row((*funcctx).tuple_desc, item) )
+}
+
+unsafe fn next_with<I, F, C>(fcinfo : FunctionCallInfo, composite : bool, init : F, convert : C) -> Datum
+where
+    F : FnOnce() -> I,
+    I : Iterator,
+    C : FnOnce(*mut FuncCallContext, I::Item) -> Option<Datum>
+{
+    let rsi = (*fcinfo).resultinfo as *mut ReturnSetInfo;
+    if rsi.is_null() || (*rsi).allowed_modes & SFRM_VALUE_PER_CALL == 0 {
+        log::Error::raise("Set-valued function called in context that cannot accept a set");
+    }
+
+    // Switches back to the previous memory context when dropped, so it is restored even if
+    // building the iterator raises an error or panics.
+    struct Restore(MemoryContext);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            unsafe { ffi::CurrentMemoryContext = self.0 };
+        }
+    }
+
+    if (*(*fcinfo).flinfo).fn_extra.is_null() {
+        let funcctx = ffi::init_MultiFuncCall(fcinfo);
+        let ctx = (*funcctx).multi_call_memory_ctx;
+        let restore = Restore(ffi::CurrentMemoryContext);
+        ffi::CurrentMemoryContext = ctx;
+        if composite {
+            (*funcctx).tuple_desc = row::result_desc(fcinfo);
+        }
+        let iter = Box::into_raw(Box::new(init()));
+        let cb = ffi::MemoryContextAlloc(ctx, mem::size_of::<MemoryContextCallback>()) as *mut MemoryContextCallback;
+        cb.write(MemoryContextCallback {
+            func : Some(drop_iterator::<I>),
+            arg : iter as *mut c_void,
+            next : ptr::null_mut()
+        });
+        ffi::MemoryContextRegisterResetCallback(ctx, cb);
+        drop(restore);
+        (*funcctx).user_fctx = iter as *mut c_void;
+    }
+
+    let funcctx = ffi::per_MultiFuncCall(fcinfo);
+    let iter = &mut *((*funcctx).user_fctx as *mut I);
+    match iter.next() {
+        Some(item) => {
+            (*funcctx).call_cntr += 1;
+            (*rsi).is_done = EXPR_MULTIPLE_RESULT;
+            match convert(funcctx, item) {
+                Some(datum) => datum,
+                None => {
+                    (*fcinfo).isnull = true;
+                    Datum::default()
+                }
+            }
+        },
+        None => {
+            ffi::end_MultiFuncCall(fcinfo, funcctx);
+            (*rsi).is_done = EXPR_END_RESULT;
+            (*fcinfo).isnull = true;
+            Datum::default()
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::cmp;
+use super::vla::{self, varlena};
+use super::{ffi, error, Bytea};
+use super::datum::Datum;
+
+/// Size of the slices fetched by ToastReader at each call to pg_detoast_datum_slice.
+pub const READ_CHUNK_SIZE : usize = 64 * 1024;
+
+/// Size of the data of a possibly toasted value, without fetching or decompressing it.
+pub(crate) fn raw_len(value : *const varlena) -> usize {
+    unsafe { ffi::toast_raw_datum_size(Datum::from_ptr(value)) - vla::VARHDRSZ }
+}
+
+/// Copies len bytes of the value data starting at offset into a new palloc'd buffer. Only the
+/// TOAST chunks (or the compressed prefix) required to produce the slice are read. The slice is
+/// truncated at the end of the value.
+pub(crate) fn slice(value : *const varlena, offset : usize, len : usize) -> Bytea {
+    let total = raw_len(value);
+    let offset = cmp::min(offset, total);
+    let len = cmp::min(len, total - offset);
+    unsafe { Bytea::new(ffi::pg_detoast_datum_slice(value as *mut _, offset as i32, len as i32)) }
+}
+
+/// Reads a bytea or text value incrementally, fetching slices of READ_CHUNK_SIZE bytes
+/// at a time. Only the slice currently being read is held in memory, so multi-megabyte
+/// values can be processed with bounded memory:
+///
+/// ```rust,ignore
+/// #[pg_extern]
+/// fn header_magic(b : Bytea) -> i32 {
+///     let mut magic = [0u8; 4];
+///     b.reader().read_exact(&mut magic).unwrap();
+///     i32::from_be_bytes(magic)
+/// }
+/// ```
+pub struct ToastReader {
+    value : *const varlena,
+    len : usize,
+    pos : usize,
+
+    // Slice currently held, and the offset of its first byte.
+    chunk : Option<(usize, Bytea)>
+}
+
+impl ToastReader {
+
+    pub(crate) fn new(value : *const varlena) -> Self {
+        ToastReader { value, len : raw_len(value), pos : 0, chunk : None }
+    }
+
+    /// Size of the value data.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn release_chunk(&mut self) {
+        if let Some((_, chunk)) = self.chunk.take() {
+            unsafe { ffi::pfree(chunk.as_ptr() as *mut _) };
+        }
+    }
+
+}
+
+impl Read for ToastReader {
+
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let in_chunk = match &self.chunk {
+            Some((start, chunk)) => self.pos >= *start && self.pos < start + chunk.as_ref().len(),
+            None => false
+        };
+        if !in_chunk {
+            self.release_chunk();
+            self.chunk = Some((self.pos, slice(self.value, self.pos, READ_CHUNK_SIZE)));
+        }
+        let (start, chunk) = self.chunk.as_ref().unwrap();
+        let data = &chunk.as_ref()[(self.pos - start)..];
+        let n = cmp::min(buf.len(), data.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+
+}
+
+impl Seek for ToastReader {
+
+    fn seek(&mut self, pos : SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p as i64),
+            SeekFrom::End(delta) => (self.len as i64).checked_add(delta),
+            SeekFrom::Current(delta) => (self.pos as i64).checked_add(delta)
+        };
+        match new_pos {
+            Some(p) if p >= 0 => {
+                self.pos = p as usize;
+                Ok(self.pos as u64)
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek to a negative position"))
+        }
+    }
+
+}
+
+impl Drop for ToastReader {
+
+    fn drop(&mut self) {
+        if !error::unwinding_error() {
+            self.release_chunk();
+        }
+    }
+
+}
@@ -0,0 +1,351 @@
+use std::os::raw::{c_char, c_int, c_void};
+use std::ffi::CStr;
+use super::datum::{Datum, Oid};
+use super::fmgr::FunctionCallInfo;
+use super::row::{Row, TupleDesc, HeapTupleData};
+use super::spi::Spi;
+use super::{ffi, log, pg_config, Text};
+
+/// ABI-compatible with TriggerData (trigger.h), which the server passes as the call context of
+/// trigger functions.
+#[repr(C)]
+struct TriggerData {
+    node_type : c_int,
+    tg_event : u32,
+    tg_relation : *mut RelationData,
+    tg_trigtuple : *mut HeapTupleData,
+    tg_newtuple : *mut HeapTupleData,
+    tg_trigger : *mut TriggerDef,
+    tg_trigslot : *mut c_void,
+    tg_newslot : *mut c_void,
+    tg_oldtable : *mut c_void,
+    tg_newtable : *mut c_void,
+    tg_updatedcols : *const c_void
+}
+
+/// Prefix of RelationData (rel.h), up to the relation oid. Relations are otherwise handled through
+/// server functions, and only rd_att and rd_id are read.
+#[repr(C)]
+pub(crate) struct RelationData {
+    rd_locator : [Oid; 3],
+    rd_smgr : *mut c_void,
+    rd_refcnt : c_int,
+    rd_backend : c_int,
+    rd_islocaltemp : bool,
+    rd_isnailed : bool,
+    rd_isvalid : bool,
+    rd_indexvalid : bool,
+    rd_statvalid : bool,
+    rd_create_subid : u32,
+    rd_new_relfilelocator_subid : u32,
+    rd_first_relfilelocator_subid : u32,
+    rd_dropped_subid : u32,
+    rd_rel : *mut c_void,
+    rd_att : TupleDesc,
+    rd_id : Oid
+}
+
+// RelationData is not part of the interface kept stable for extensions, and the offsets of rd_att
+// and rd_id were checked against the headers of versions 13 to 18 only.
+const _ : () = assert!(
+    pg_config::PG_VERSION_NUM < 190000,
+    "The offsets of RelationData.rd_att and RelationData.rd_id must be checked for this server version"
+);
+
+/// Prefix of Trigger (reltrigger.h), up to the trigger arguments.
+#[repr(C)]
+struct TriggerDef {
+    tgoid : Oid,
+    tgname : *mut c_char,
+    tgfoid : Oid,
+    tgtype : i16,
+    tgenabled : c_char,
+    tgisinternal : bool,
+    tgisclone : bool,
+    tgconstrrelid : Oid,
+    tgconstrindid : Oid,
+    tgconstraint : Oid,
+    tgdeferrable : bool,
+    tginitdeferred : bool,
+    tgnargs : i16,
+    tgnattr : i16,
+    tgattr : *mut i16,
+    tgargs : *mut *mut c_char
+}
+
+// TriggerEvent bits (trigger.h)
+const TRIGGER_EVENT_OPMASK : u32 = 0x03;
+
+const TRIGGER_EVENT_ROW : u32 = 0x04;
+
+const TRIGGER_EVENT_BEFORE : u32 = 0x08;
+
+const TRIGGER_EVENT_INSTEAD : u32 = 0x10;
+
+/// Operation that fired a trigger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerEvent {
+    Insert,
+    Update,
+    Delete,
+    Truncate
+}
+
+/// Whether a trigger fires before, after or instead of the operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerTiming {
+    Before,
+    After,
+    InsteadOf
+}
+
+/// Whether a trigger fires once for each affected row, or once for the statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerLevel {
+    Row,
+    Statement
+}
+
+/// Information the server passes to a trigger function. Functions annotated with #[pg_trigger]
+/// take a &Trigger and return the row the operation should proceed with, the same way PL/pgSQL
+/// trigger functions return NEW, OLD or NULL:
+///
+/// ```rust,ignore
+/// #[pg_trigger]
+/// fn stamp(trigger : &Trigger) -> Option<Row> {
+///     let mut new = trigger.new_row()?;
+///     new.set("modified_by", Text::from(&trigger.args().join(" ")));
+///     Some(new)
+/// }
+/// ```
+///
+/// The trigger is then attached to a table as any other trigger function:
+///
+/// ```sql
+/// create trigger stamp before insert or update on items for each row execute function stamp('api');
+/// ```
+///
+/// For row-level BEFORE triggers, returning None skips the operation on the row, and returning
+/// a row (possibly modified with Row::set) makes the operation use it (for INSERT and UPDATE)
+/// or proceed (for DELETE, which conventionally returns the old row). The result of AFTER
+/// triggers is ignored, and statement-level triggers must return None.
+pub struct Trigger {
+    data : *mut TriggerData
+}
+
+impl Trigger {
+
+    /// Name of the trigger (as given at CREATE TRIGGER).
+    pub fn name(&self) -> String {
+        unsafe { CStr::from_ptr((*(*self.data).tg_trigger).tgname).to_string_lossy().into_owned() }
+    }
+
+    pub fn event(&self) -> TriggerEvent {
+        match unsafe { (*self.data).tg_event } & TRIGGER_EVENT_OPMASK {
+            0 => TriggerEvent::Insert,
+            1 => TriggerEvent::Delete,
+            2 => TriggerEvent::Update,
+            _ => TriggerEvent::Truncate
+        }
+    }
+
+    pub fn timing(&self) -> TriggerTiming {
+        let event = unsafe { (*self.data).tg_event };
+        if event & TRIGGER_EVENT_BEFORE != 0 {
+            TriggerTiming::Before
+        } else if event & TRIGGER_EVENT_INSTEAD != 0 {
+            TriggerTiming::InsteadOf
+        } else {
+            TriggerTiming::After
+        }
+    }
+
+    pub fn level(&self) -> TriggerLevel {
+        if unsafe { (*self.data).tg_event } & TRIGGER_EVENT_ROW != 0 {
+            TriggerLevel::Row
+        } else {
+            TriggerLevel::Statement
+        }
+    }
+
+    /// Oid of the table (or view) the trigger fired for.
+    pub fn table_oid(&self) -> Oid {
+        unsafe { (*(*self.data).tg_relation).rd_id }
+    }
+
+    /// Name of the table (or view) the trigger fired for.
+    pub fn table_name(&self) -> String {
+        unsafe { owned_name(ffi::SPI_getrelname((*self.data).tg_relation)) }
+    }
+
+    /// Schema of the table (or view) the trigger fired for.
+    pub fn table_schema(&self) -> String {
+        unsafe { owned_name(ffi::SPI_getnspname((*self.data).tg_relation)) }
+    }
+
+    /// Arguments given to the trigger function at CREATE TRIGGER.
+    pub fn args(&self) -> Vec<String> {
+        unsafe {
+            let trigger = &*(*self.data).tg_trigger;
+            (0..trigger.tgnargs as usize)
+                .map(|i| CStr::from_ptr(*trigger.tgargs.add(i)).to_string_lossy().into_owned() )
+                .collect()
+        }
+    }
+
+    /// Row before the operation (OLD), for row-level UPDATE and DELETE triggers.
+    pub fn old_row(&self) -> Option<Row> {
+        match (self.level(), self.event()) {
+            (TriggerLevel::Row, TriggerEvent::Update) | (TriggerLevel::Row, TriggerEvent::Delete) => {
+                unsafe { Some(self.row((*self.data).tg_trigtuple)) }
+            },
+            _ => None
+        }
+    }
+
+    /// Row after the operation (NEW), for row-level INSERT and UPDATE triggers.
+    pub fn new_row(&self) -> Option<Row> {
+        match (self.level(), self.event()) {
+            (TriggerLevel::Row, TriggerEvent::Insert) => unsafe { Some(self.row((*self.data).tg_trigtuple)) },
+            (TriggerLevel::Row, TriggerEvent::Update) => unsafe { Some(self.row((*self.data).tg_newtuple)) },
+            _ => None
+        }
+    }
+
+    unsafe fn row(&self, tuple : *mut HeapTupleData) -> Row {
+        Row::from_tuple(tuple, (*(*self.data).tg_relation).rd_att)
+    }
+
+}
+
+// Copies a palloc'd name returned by SPI_getrelname or SPI_getnspname (the schema name is
+// looked up at the catalog, and is NULL if the schema was concurrently dropped).
+unsafe fn owned_name(name : *mut c_char) -> String {
+    if name.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(name).to_string_lossy().into_owned()
+    }
+}
+
+/// Calls a trigger function with the trigger data of the call, and converts the row it returns
+/// into the tuple the server proceeds with. Rows read from the trigger data and returned
+/// unmodified are passed back as-is.
+pub unsafe fn call<F>(fcinfo : FunctionCallInfo, f : F) -> Datum
+where
+    F : FnOnce(&Trigger) -> Option<Row>
+{
+    let data = (*fcinfo).context as *mut TriggerData;
+    if data.is_null() {
+        log::Error::raise("Trigger function called outside of a trigger");
+    }
+    let trigger = Trigger { data };
+    let row = match f(&trigger) {
+        Some(row) => row,
+        None => return Datum::default()
+    };
+    let rel_desc = (*(*data).tg_relation).rd_att;
+    if (*row.desc()).tdtypeid != (*rel_desc).tdtypeid {
+        log::Error::raise(&format!("Trigger {} returned a row of a type other than the row type of its table", trigger.name()));
+    }
+    for tuple in [(*data).tg_trigtuple, (*data).tg_newtuple] {
+        if !tuple.is_null() && (*tuple).t_data == row.tuple().t_data {
+            return Datum::from_ptr(tuple);
+        }
+    }
+    Datum::from_ptr(ffi::heap_copytuple(row.tuple() as *const _ as *mut _))
+}
+
+/// ABI-compatible with EventTriggerData (event_trigger.h), which the server passes as the call
+/// context of event trigger functions.
+#[repr(C)]
+struct EventTriggerData {
+    node_type : c_int,
+    event : *const c_char,
+    parsetree : *mut c_void,
+    tag : c_int
+}
+
+/// Information the server passes to an event trigger function. Functions annotated with
+/// #[pg_event_trigger] take an &EventTrigger, and the attribute can also create the event
+/// trigger that calls them:
+///
+/// ```rust,ignore
+/// #[pg_event_trigger(event = "ddl_command_end", tags("CREATE TABLE", "ALTER TABLE"))]
+/// fn log_ddl(trigger : &EventTrigger) {
+///     for cmd in trigger.ddl_commands() {
+///         log::Notice::raise(&format!("{} {}", trigger.tag(), cmd.object_identity));
+///     }
+/// }
+/// ```
+pub struct EventTrigger {
+    data : *mut EventTriggerData
+}
+
+/// A command executed by the statement that fired a ddl_command_end event trigger, as listed
+/// by pg_event_trigger_ddl_commands().
+#[derive(Debug, Clone, PartialEq)]
+pub struct DdlCommand {
+    pub class_id : Oid,
+    pub object_id : Oid,
+    pub object_sub_id : i32,
+    pub command_tag : String,
+    pub object_type : String,
+    pub schema_name : Option<String>,
+    pub object_identity : String,
+    pub in_extension : bool
+}
+
+impl EventTrigger {
+
+    /// Event the trigger fired on (ddl_command_start, ddl_command_end, sql_drop, etc.).
+    pub fn event(&self) -> String {
+        unsafe { CStr::from_ptr((*self.data).event).to_string_lossy().into_owned() }
+    }
+
+    /// Command tag of the statement that fired the trigger (such as CREATE TABLE).
+    pub fn tag(&self) -> String {
+        unsafe { CStr::from_ptr(ffi::GetCommandTagName((*self.data).tag)).to_string_lossy().into_owned() }
+    }
+
+    /// Commands executed by the statement, read from pg_event_trigger_ddl_commands(). They are
+    /// only known at ddl_command_end (the list is empty at ddl_command_start, and the server
+    /// raises an error for the other events).
+    pub fn ddl_commands(&self) -> Vec<DdlCommand> {
+        let query = "select classid, objid, objsubid, command_tag, object_type, schema_name, \
+            object_identity, in_extension from pg_catalog.pg_event_trigger_ddl_commands()";
+        let result = Spi::connect_read_only()
+            .and_then(|spi| spi.execute(query, &[]) )
+            .unwrap_or_else(|e| log::Error::raise(&format!("Could not list DDL commands: {}", e)) );
+        result.rows().iter().map(|row| {
+            let text = |name : &str| -> Option<String> {
+                let value : Option<Text> = row.get(name);
+                value.map(|t| t.as_ref().to_string() )
+            };
+            DdlCommand {
+                class_id : row.get("classid"),
+                object_id : row.get("objid"),
+                object_sub_id : row.get("objsubid"),
+                command_tag : text("command_tag").unwrap_or_default(),
+                object_type : text("object_type").unwrap_or_default(),
+                schema_name : text("schema_name"),
+                object_identity : text("object_identity").unwrap_or_default(),
+                in_extension : row.get("in_extension")
+            }
+        }).collect()
+    }
+
+}
+
+/// Calls an event trigger function with the event trigger data of the call.
+pub unsafe fn call_event<F>(fcinfo : FunctionCallInfo, f : F) -> Datum
+where
+    F : FnOnce(&EventTrigger)
+{
+    let data = (*fcinfo).context as *mut EventTriggerData;
+    if data.is_null() {
+        log::Error::raise("Event trigger function called outside of an event trigger");
+    }
+    f(&EventTrigger { data });
+    Datum::default()
+}
@@ -1,6 +1,7 @@
 #[repr(C)]
 #[derive(Default)]
 struct __IncompleteArrayField<T>(::std::marker::PhantomData<T>, [T; 0]);
+#[allow(dead_code, clippy::useless_transmute)]
 impl<T> __IncompleteArrayField<T> {
     #[inline]
     pub const fn new() -> Self {
@@ -37,6 +38,7 @@ impl<T> ::std::clone::Clone for __IncompleteArrayField<T> {
 
 #[repr(C)]
 struct __BindgenUnionField<T>(::std::marker::PhantomData<T>);
+#[allow(dead_code, clippy::useless_transmute)]
 impl<T> __BindgenUnionField<T> {
     #[inline]
     pub const fn new() -> Self {
@@ -59,3 +61,156 @@ pub struct varlena {
     vl_dat: __IncompleteArrayField<::std::os::raw::c_char>,
 }
 
+
+// Rust translations of the varlena header macros at postgres.h. A varlena starts either with a
+// 4-byte header, holding the total size of the value (header included), or with a 1-byte header,
+// used for short values stored on disk. An 1-byte header with zero length marks a TOAST pointer,
+// whose size is given by the tag at the following byte.
+
+pub const VARHDRSZ : usize = 4;
+
+const VARHDRSZ_SHORT : usize = 1;
+
+const VARHDRSZ_EXTERNAL : usize = 2;
+
+#[cfg(target_endian = "little")]
+mod header {
+
+    pub fn is_4b_u(first : u8) -> bool {
+        first & 0x03 == 0x00
+    }
+
+    pub fn is_1b(first : u8) -> bool {
+        first & 0x01 == 0x01
+    }
+
+    pub fn is_1b_e(first : u8) -> bool {
+        first == 0x01
+    }
+
+    pub fn size_4b(header : u32) -> usize {
+        ((header >> 2) & 0x3FFF_FFFF) as usize
+    }
+
+    pub fn size_1b(first : u8) -> usize {
+        ((first >> 1) & 0x7F) as usize
+    }
+
+    pub fn header_4b(len : usize) -> u32 {
+        (len as u32) << 2
+    }
+
+}
+
+#[cfg(target_endian = "big")]
+mod header {
+
+    pub fn is_4b_u(first : u8) -> bool {
+        first & 0xC0 == 0x00
+    }
+
+    pub fn is_1b(first : u8) -> bool {
+        first & 0x80 == 0x80
+    }
+
+    pub fn is_1b_e(first : u8) -> bool {
+        first == 0x80
+    }
+
+    pub fn size_4b(header : u32) -> usize {
+        (header & 0x3FFF_FFFF) as usize
+    }
+
+    pub fn size_1b(first : u8) -> usize {
+        (first & 0x7F) as usize
+    }
+
+    pub fn header_4b(len : usize) -> u32 {
+        (len as u32) & 0x3FFF_FFFF
+    }
+
+}
+
+// VARTAG_SIZE: the indirect and expanded pointers hold a single pointer, while on-disk
+// pointers are varatt_external (raw size, external size, value oid, toast relation oid).
+fn vartag_size(tag : u8) -> usize {
+    match tag {
+        1..=3 => std::mem::size_of::<*const u8>(),
+        18 => 16,
+        _ => 0
+    }
+}
+
+unsafe fn first_byte(ptr : *const varlena) -> u8 {
+    *(ptr as *const u8)
+}
+
+/// VARATT_IS_EXTENDED: Whether the value is not a plain uncompressed value with a 4-byte
+/// header (i.e. it has a short header, is compressed or is a TOAST pointer).
+pub unsafe fn is_extended(ptr : *const varlena) -> bool {
+    !header::is_4b_u(first_byte(ptr))
+}
+
+/// VARSIZE_ANY: Total size of the value, including its header.
+pub unsafe fn varsize_any(ptr : *const varlena) -> usize {
+    let first = first_byte(ptr);
+    if header::is_1b_e(first) {
+        VARHDRSZ_EXTERNAL + vartag_size(*(ptr as *const u8).add(1))
+    } else if header::is_1b(first) {
+        header::size_1b(first)
+    } else {
+        header::size_4b((ptr as *const u32).read_unaligned())
+    }
+}
+
+/// VARSIZE_ANY_EXHDR: Size of the value data, excluding its header.
+pub unsafe fn varsize_any_exhdr(ptr : *const varlena) -> usize {
+    let first = first_byte(ptr);
+    if header::is_1b_e(first) {
+        varsize_any(ptr) - VARHDRSZ_EXTERNAL
+    } else if header::is_1b(first) {
+        varsize_any(ptr) - VARHDRSZ_SHORT
+    } else {
+        varsize_any(ptr) - VARHDRSZ
+    }
+}
+
+/// VARDATA_ANY: Pointer to the value data, right after its header.
+pub unsafe fn vardata_any(ptr : *const varlena) -> *const u8 {
+    if header::is_1b(first_byte(ptr)) {
+        (ptr as *const u8).add(VARHDRSZ_SHORT)
+    } else {
+        (ptr as *const u8).add(VARHDRSZ)
+    }
+}
+
+/// SET_VARSIZE: Writes a 4-byte header for a value with the given total size (header included).
+pub unsafe fn set_varsize(ptr : *mut varlena, len : usize) {
+    (ptr as *mut u32).write_unaligned(header::header_4b(len));
+}
+
+#[test]
+fn varlena_headers() {
+    let mut buf = [0u8; 9];
+    buf[4..].copy_from_slice(b"hello");
+    let ptr = buf.as_mut_ptr() as *mut varlena;
+    unsafe {
+        set_varsize(ptr, VARHDRSZ + 5);
+        assert!(!is_extended(ptr));
+        assert_eq!(varsize_any(ptr), 9);
+        assert_eq!(varsize_any_exhdr(ptr), 5);
+        assert_eq!(std::slice::from_raw_parts(vardata_any(ptr), 5), b"hello");
+    }
+
+    // Short header, as written by SET_VARSIZE_1B
+    let mut short = [0u8; 6];
+    short[0] = if cfg!(target_endian = "little") { (6 << 1) | 0x01 } else { 6 | 0x80 };
+    short[1..].copy_from_slice(b"hello");
+    let ptr = short.as_ptr() as *const varlena;
+    unsafe {
+        assert!(is_extended(ptr));
+        assert_eq!(varsize_any(ptr), 6);
+        assert_eq!(varsize_any_exhdr(ptr), 5);
+        assert_eq!(std::slice::from_raw_parts(vardata_any(ptr), 5), b"hello");
+    }
+}
@@ -0,0 +1,49 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use super::error::{self, ErrorData, ServerError};
+use super::ffi;
+
+/// Runs f inside a subtransaction. If f raises a server error (e.g. an SPI query violates a
+/// constraint), the subtransaction is rolled back, undoing whatever f changed, and the error is
+/// returned; the calling transaction goes on normally. Otherwise the subtransaction is released,
+/// and the changes become part of the calling transaction:
+///
+/// ```rust,ignore
+/// let spi = Spi::connect().unwrap();
+/// let inserted = xact::subtransaction(|| {
+///     spi.execute("insert into users (name) values ($1)", &[name.into()]).unwrap()
+/// });
+/// match inserted {
+///     Err(e) if e.sqlstate == "23505" => log::Notice::raise(&format!("{} already exists", name)),
+///     Err(e) => log::Error::raise(&e.message),
+///     Ok(_) => { }
+/// }
+/// ```
+///
+/// The Rust frames inside f are unwound before the subtransaction is rolled back (see
+/// error::guard). Subtransactions are rolled back on panics as well, before the panic resumes.
+#[allow(clippy::result_large_err)]
+pub fn subtransaction<R, F : FnOnce() -> R>(f : F) -> Result<R, ErrorData> {
+    unsafe {
+        let context = ffi::CurrentMemoryContext;
+        let owner = ffi::CurrentResourceOwner;
+
+        // Starting a subtransaction switches to its memory context, which is released with it.
+        ffi::BeginInternalSubTransaction(ptr::null());
+        ffi::CurrentMemoryContext = context;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| error::guard(f) ));
+        match result {
+            Ok(_) => ffi::ReleaseCurrentSubTransaction(),
+            Err(_) => ffi::RollbackAndReleaseCurrentSubTransaction()
+        }
+        ffi::CurrentMemoryContext = context;
+        ffi::CurrentResourceOwner = owner;
+        match result {
+            Ok(value) => Ok(value),
+            Err(payload) => match payload.downcast::<ServerError>() {
+                Ok(error) => Err(error.into_data()),
+                Err(payload) => panic::resume_unwind(payload)
+            }
+        }
+    }
+}